@@ -11,11 +11,12 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::iter::{Extend, FromIterator};
 
+use treap::arena::Arena;
 use treap::TreapMap;
 
 #[bench]
 fn bench_treap_insert(b: &mut Bencher) {
-    let seed = [1, 2, 3, 5];
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     let rng: XorShiftRng = SeedableRng::from_seed(seed);
 
     b.iter(|| {
@@ -52,17 +53,17 @@ fn bench_hash_insert(b: &mut Bencher) {
 #[bench]
 fn bench_treap_find(b: &mut Bencher) {
     use rand::Rng;
-    let mut nums: Vec<_> = (1..1000).into_iter().collect();
-    let seed = [1, 2, 3, 5];
+    let mut nums: Vec<_> = (1..1000).collect();
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     let mut rng: XorShiftRng = SeedableRng::from_seed(seed);
     rng.shuffle(&mut nums);
     let mut t = TreapMap::new_with_rng(rng);
-    t.extend((&nums).into_iter().map(|k| (*k, *k)));
+    t.extend(nums.iter().map(|k| (*k, *k)));
 
     b.iter(|| {
         let mut sum = 0u32;
         for i in &nums[1..300] {
-            sum += *t.get(&i).unwrap();
+            sum += *t.get(i).unwrap();
         }
         sum
     })
@@ -71,11 +72,11 @@ fn bench_treap_find(b: &mut Bencher) {
 #[bench]
 fn bench_btree_find(b: &mut Bencher) {
     use rand::Rng;
-    let mut nums: Vec<_> = (1..1000).into_iter().collect();
-    let seed: &[_] = &[1, 2, 3, 4];
+    let mut nums: Vec<_> = (1..1000).collect();
+    let seed = [1; 32];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
     rng.shuffle(&mut nums);
-    let t = BTreeMap::from_iter((&nums).into_iter().map(|k| (*k, *k)));
+    let t = BTreeMap::from_iter(nums.iter().map(|k| (*k, *k)));
 
     b.iter(|| {
         let mut sum = 0u32;
@@ -89,17 +90,70 @@ fn bench_btree_find(b: &mut Bencher) {
 #[bench]
 fn bench_hash_find(b: &mut Bencher) {
     use rand::Rng;
-    let mut nums: Vec<_> = (1..1000).into_iter().collect();
-    let seed: &[_] = &[1, 2, 3, 4];
+    let mut nums: Vec<_> = (1..1000).collect();
+    let seed = [1; 32];
     let mut rng: StdRng = SeedableRng::from_seed(seed);
     rng.shuffle(&mut nums);
-    let t: HashMap<u32, u32> = HashMap::from_iter((&nums).into_iter().map(|k| (*k, *k)));
+    let t: HashMap<u32, u32> = HashMap::from_iter(nums.iter().map(|k| (*k, *k)));
+
+    b.iter(|| {
+        let mut sum = 0u32;
+        for i in &nums[1..300] {
+            sum += *t.get(i).unwrap();
+        }
+        sum
+    })
+}
+
+#[bench]
+fn bench_arena_treap_insert(b: &mut Bencher) {
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let rng: XorShiftRng = SeedableRng::from_seed(seed);
+
+    b.iter(|| {
+        let mut t = TreapMap::new_with_priorities_in(rng.clone(), Arena::new());
+        for i in 0..500i32 {
+            t.insert(i, i);
+        }
+        t
+    })
+}
+
+#[bench]
+fn bench_arena_treap_find(b: &mut Bencher) {
+    use rand::Rng;
+    let mut nums: Vec<_> = (1..1000).collect();
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut rng: XorShiftRng = SeedableRng::from_seed(seed);
+    rng.shuffle(&mut nums);
+    let mut t = TreapMap::new_with_priorities_in(rng, Arena::new());
+    t.extend(nums.iter().map(|k| (*k, *k)));
 
     b.iter(|| {
         let mut sum = 0u32;
         for i in &nums[1..300] {
-            sum += *t.get(&i).unwrap();
+            sum += *t.get(i).unwrap();
         }
         sum
     })
 }
+
+#[bench]
+fn bench_treap_clone(b: &mut Bencher) {
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let rng: XorShiftRng = SeedableRng::from_seed(seed);
+    let mut t = TreapMap::new_with_rng(rng);
+    t.extend((0..1000u32).map(|k| (k, k)));
+
+    b.iter(|| t.clone())
+}
+
+#[bench]
+fn bench_arena_treap_clone(b: &mut Bencher) {
+    let seed = [1, 2, 3, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let rng: XorShiftRng = SeedableRng::from_seed(seed);
+    let mut t = TreapMap::new_with_priorities_in(rng, Arena::new());
+    t.extend((0..1000u32).map(|k| (k, k)));
+
+    b.iter(|| t.clone())
+}
@@ -35,10 +35,10 @@ fn main() {
     }
 
     let mut r = TreapMap::new();
-    r.extend(vec![(1, 200), (2, 120), (3, 330)].into_iter());
+    r.extend(vec![(1, 200), (2, 120), (3, 330)]);
 
     for (k, v) in &mut r {
-        *v = *v + *k;
+        *v += *k;
     }
     println!("{:?}", r.get(&2));
 }
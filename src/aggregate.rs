@@ -0,0 +1,448 @@
+//! Cached aggregates of subtrees
+//!
+//! The last type parameter of a `TreapMap` is an `Aggregate`. The treap stores in every node a
+//! summary of the entries in the node's subtree and keeps the summaries up to date as it changes,
+//! which makes it possible to combine the entries in any key range in O(log n) expected time.
+//! Examples are the sum or maximum of the values in a range, or the number of keys in a range.
+//! The default aggregate `()` keeps no summaries. The subtree sizes that every treap keeps for
+//! `rank` and `select` are the summaries of `Count`.
+
+use std::borrow::Borrow;
+use std::ops::{Add, RangeBounds};
+
+use rand::prng::XorShiftRng;
+
+use map::{before_start, check_range, within_end, TreapMap};
+use node::{Link, Node, Store};
+use priority::PrioritySource;
+use storage::{Boxed, Storage};
+
+/// A way to summarize the entries of a treap. The summaries with `combine` must form a monoid:
+/// `combine` has to be associative and `identity` has to be neutral with respect to it. Summaries
+/// are always combined in key order, so `combine` does not need to be commutative.
+pub trait Aggregate<K, V> {
+    /// The summary of a sequence of entries.
+    type Summary: Clone;
+
+    /// The summary of no entries.
+    fn identity() -> Self::Summary;
+
+    /// The summary of a single entry.
+    fn summarize(key: &K, value: &V) -> Self::Summary;
+
+    /// The summary of the entries summarized by `left` followed by the entries summarized by
+    /// `right`.
+    fn combine(left: &Self::Summary, right: &Self::Summary) -> Self::Summary;
+}
+
+/// Keeps no summaries. This is the aggregate of a `TreapMap` unless another one is given.
+impl<K, V> Aggregate<K, V> for () {
+    type Summary = ();
+
+    fn identity() {}
+
+    fn summarize(_key: &K, _value: &V) {}
+
+    fn combine(_left: &(), _right: &()) {}
+}
+
+/// Counts the entries. The count of the keys below a key is its rank. Every treap keeps these
+/// counts as the sizes of its subtrees, whatever its aggregate.
+#[derive(Debug, Clone, Copy)]
+pub struct Count;
+
+impl<K, V> Aggregate<K, V> for Count {
+    type Summary = usize;
+
+    fn identity() -> usize {
+        0
+    }
+
+    fn summarize(_key: &K, _value: &V) -> usize {
+        1
+    }
+
+    fn combine(left: &usize, right: &usize) -> usize {
+        left + right
+    }
+}
+
+/// Sums the values, starting from their default value.
+#[derive(Debug, Clone, Copy)]
+pub struct Sum;
+
+impl<K, V: Clone + Default + Add<Output = V>> Aggregate<K, V> for Sum {
+    type Summary = V;
+
+    fn identity() -> V {
+        V::default()
+    }
+
+    fn summarize(_key: &K, value: &V) -> V {
+        value.clone()
+    }
+
+    fn combine(left: &V, right: &V) -> V {
+        left.clone() + right.clone()
+    }
+}
+
+/// Finds the largest value, if there are any entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Max;
+
+impl<K, V: Clone + Ord> Aggregate<K, V> for Max {
+    type Summary = Option<V>;
+
+    fn identity() -> Option<V> {
+        None
+    }
+
+    fn summarize(_key: &K, value: &V) -> Option<V> {
+        Some(value.clone())
+    }
+
+    fn combine(left: &Option<V>, right: &Option<V>) -> Option<V> {
+        left.clone().max(right.clone())
+    }
+}
+
+/// Finds the smallest value, if there are any entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Min;
+
+impl<K, V: Clone + Ord> Aggregate<K, V> for Min {
+    type Summary = Option<V>;
+
+    fn identity() -> Option<V> {
+        None
+    }
+
+    fn summarize(_key: &K, value: &V) -> Option<V> {
+        Some(value.clone())
+    }
+
+    fn combine(left: &Option<V>, right: &Option<V>) -> Option<V> {
+        match (left, right) {
+            (Some(l), Some(r)) => Some(l.min(r).clone()),
+            (Some(v), None) | (None, Some(v)) => Some(v.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Computes two aggregates side by side.
+impl<K, V, A: Aggregate<K, V>, B: Aggregate<K, V>> Aggregate<K, V> for (A, B) {
+    type Summary = (A::Summary, B::Summary);
+
+    fn identity() -> Self::Summary {
+        (A::identity(), B::identity())
+    }
+
+    fn summarize(key: &K, value: &V) -> Self::Summary {
+        (A::summarize(key, value), B::summarize(key, value))
+    }
+
+    fn combine(left: &Self::Summary, right: &Self::Summary) -> Self::Summary {
+        (A::combine(&left.0, &right.0), B::combine(&left.1, &right.1))
+    }
+}
+
+/// A map based on a randomized treap that keeps an aggregate of every subtree. This is a
+/// `TreapMap` with boxed nodes and the aggregate `A`.
+///
+/// ```
+/// use treap::aggregate::{AugmentedTreapMap, Sum};
+///
+/// let mut t = AugmentedTreapMap::<_, _, Sum>::default();
+/// t.insert(1, 10);
+/// t.insert(2, 20);
+/// assert_eq!(t.aggregate(..), 30);
+/// *t.get_mut(&1).unwrap() += 5;
+/// assert_eq!(t.aggregate(..), 35);
+/// ```
+pub type AugmentedTreapMap<K, V, A, P = XorShiftRng> = TreapMap<K, V, P, Boxed, A>;
+
+impl<K, V, P, S, A> TreapMap<K, V, P, S, A>
+where
+    K: Ord,
+    P: PrioritySource<K>,
+    S: Storage<K, V, A>,
+    A: Aggregate<K, V>,
+{
+    /// Combine the summaries of the entries with keys in the given range, in key order. Takes
+    /// O(log n) expected time. Values changed through mutable references are summarized again
+    /// when the treap is next changed otherwise, and until then by each call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// use treap::aggregate::{AugmentedTreapMap, Count, Max, Sum};
+    ///
+    /// let t: AugmentedTreapMap<_, _, (Sum, Max)> = (1..10).map(|k| (k, k * 10)).collect();
+    /// assert_eq!(t.aggregate(3..6), (120, Some(50)));
+    ///
+    /// // The number of keys in a range
+    /// let t: AugmentedTreapMap<_, _, Count> = (1..10).map(|k| (k * 2, ())).collect();
+    /// assert_eq!(t.aggregate(3..7), 2);
+    /// ```
+    pub fn aggregate<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> A::Summary
+    where
+        K: Borrow<Q>,
+    {
+        let (store, root) = self.tree();
+        range_summary(store, root, range)
+    }
+}
+
+/// Combine the summaries of the entries of the tree at `root` with keys in the given range.
+pub(crate) fn range_summary<K, V, S, A, Q, R>(
+    store: &S,
+    root: &Link<K, V, S, A>,
+    range: R,
+) -> A::Summary
+where
+    K: Ord + Borrow<Q>,
+    S: Store<K, V, A>,
+    A: Aggregate<K, V>,
+    Q: ?Sized + Ord,
+    R: RangeBounds<Q>,
+{
+    check_range(&range);
+    let (start, end) = (range.start_bound(), range.end_bound());
+    // Find the highest node in the range, where the search paths to the bounds diverge
+    let mut cur = Node::at(store, root);
+    let top = loop {
+        match cur {
+            None => return A::identity(),
+            Some(node) => {
+                if before_start(&node.key, start) {
+                    cur = Node::at(store, &node.right);
+                } else if !within_end(&node.key, end) {
+                    cur = Node::at(store, &node.left);
+                } else {
+                    break node;
+                }
+            }
+        }
+    };
+    // Below the top node everything on the left is within the end bound, so only the start
+    // bound has to be checked, and the other way around on the right
+    let mut below = A::identity();
+    let mut cur = Node::at(store, &top.left);
+    while let Some(node) = cur {
+        if before_start(&node.key, start) {
+            cur = Node::at(store, &node.right);
+        } else {
+            let part = A::combine(
+                &A::summarize(&node.key, &node.value),
+                &Node::summary(store, &node.right),
+            );
+            below = A::combine(&part, &below);
+            cur = Node::at(store, &node.left);
+        }
+    }
+    let mut above = A::identity();
+    let mut cur = Node::at(store, &top.right);
+    while let Some(node) = cur {
+        if within_end(&node.key, end) {
+            let part = A::combine(
+                &Node::summary(store, &node.left),
+                &A::summarize(&node.key, &node.value),
+            );
+            above = A::combine(&above, &part);
+            cur = Node::at(store, &node.right);
+        } else {
+            cur = Node::at(store, &node.left);
+        }
+    }
+    let middle = A::combine(&below, &A::summarize(&top.key, &top.value));
+    A::combine(&middle, &above)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aggregate, AugmentedTreapMap, Count, Max, Min, Sum};
+    use arena::Arena;
+    use map::TreapMap;
+    use node::Node;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use std::collections::BTreeMap;
+    use storage::{Shared, Storage};
+
+    type All = ((Sum, Max), (Min, Count));
+
+    // Concatenates the keys, which checks that summaries are combined in key order
+    struct Keys;
+
+    impl<V> Aggregate<u32, V> for Keys {
+        type Summary = Vec<u32>;
+
+        fn identity() -> Vec<u32> {
+            Vec::new()
+        }
+
+        fn summarize(key: &u32, _value: &V) -> Vec<u32> {
+            vec![*key]
+        }
+
+        fn combine(left: &Vec<u32>, right: &Vec<u32>) -> Vec<u32> {
+            left.iter().chain(right).cloned().collect()
+        }
+    }
+
+    fn brute_force(
+        m: &BTreeMap<u32, u32>,
+        a: u32,
+        b: u32,
+    ) -> <All as Aggregate<u32, u32>>::Summary {
+        let values: Vec<u32> = m.range(a..b).map(|(_, v)| *v).collect();
+        (
+            (values.iter().sum(), values.iter().cloned().max()),
+            (values.iter().cloned().min(), values.len()),
+        )
+    }
+
+    // Change the treap and the map alike in the ways that hand out mutable references to values,
+    // checking the aggregates of random ranges after each
+    fn change_values<S: Storage<u32, u32, All>>(
+        t: &mut TreapMap<u32, u32, XorShiftRng, S, All>,
+        m: &mut BTreeMap<u32, u32>,
+        rng: &mut XorShiftRng,
+    ) {
+        for round in 0..40 {
+            let k = rng.gen_range(0, 300);
+            match round % 8 {
+                0 => {
+                    if let Some(v) = t.get_mut(&k) {
+                        *v += 7;
+                    }
+                    if let Some(v) = m.get_mut(&k) {
+                        *v += 7;
+                    }
+                }
+                1 => {
+                    *t.entry(k).or_insert(0) += 3;
+                    *m.entry(k).or_insert(0) += 3;
+                }
+                2 => {
+                    for (_, v) in t.range_mut(k..k + 30) {
+                        *v += 1;
+                    }
+                    for (_, v) in m.range_mut(k..k + 30) {
+                        *v += 1;
+                    }
+                }
+                3 => {
+                    for v in t.values_mut().take(20) {
+                        *v += 2;
+                    }
+                    // The order of `values_mut` depends on the storage
+                    m.clear();
+                    m.extend(t.iter_ordered().map(|(k, v)| (*k, *v)));
+                }
+                4 => {
+                    for (_, v) in t.iter_ordered_mut().skip(10).take(10) {
+                        *v = v.wrapping_mul(3) % 1000;
+                    }
+                    for (_, v) in m.iter_mut().skip(10).take(10) {
+                        *v = v.wrapping_mul(3) % 1000;
+                    }
+                }
+                5 => {
+                    for (_, v) in &mut *t {
+                        *v %= 900;
+                    }
+                    for v in m.values_mut() {
+                        *v %= 900;
+                    }
+                }
+                6 => {
+                    t.retain(|k, v| {
+                        *v += 1;
+                        k % 17 != 0
+                    });
+                    m.retain(|k, v| {
+                        *v += 1;
+                        k % 17 != 0
+                    });
+                }
+                _ => {
+                    let v = rng.gen_range(0, 1000);
+                    assert_eq!(t.insert(k, v), m.insert(k, v));
+                }
+            }
+            for _ in 0..20 {
+                let a = rng.gen_range(0, 310);
+                let b = rng.gen_range(a, 310);
+                assert_eq!(t.aggregate(a..b), brute_force(m, a, b));
+            }
+        }
+        let (store, root) = t.tree();
+        Node::check_summaries(store, root);
+        t.check_invariants();
+    }
+
+    #[test]
+    fn aggregates_match_brute_force() {
+        let mut rng = XorShiftRng::from_seed([5; 16]);
+        let mut t: AugmentedTreapMap<u32, u32, All> = AugmentedTreapMap::default();
+        let mut m = BTreeMap::new();
+        for _ in 0..3000 {
+            let k = rng.gen_range(0, 300);
+            if rng.gen_range(0, 3) > 0 {
+                let v = rng.gen_range(0, 1000);
+                assert_eq!(t.insert(k, v), m.insert(k, v));
+            } else {
+                assert_eq!(t.remove(&k), m.remove(&k));
+            }
+        }
+        let (store, root) = t.tree();
+        Node::check_summaries(store, root);
+        assert_eq!(t.len(), m.len());
+        assert!(t.iter_ordered().eq(m.iter()));
+        for _ in 0..500 {
+            let a = rng.gen_range(0, 310);
+            let b = rng.gen_range(a, 310);
+            assert_eq!(t.aggregate(a..b), brute_force(&m, a, b));
+        }
+        let u = t.split_off(&150);
+        let mut n = m.split_off(&150);
+        assert_eq!(t.aggregate(..), brute_force(&m, 0, 310));
+        assert_eq!(u.aggregate(..), brute_force(&n, 0, 310));
+        t.append(&mut u.clone());
+        m.append(&mut n);
+        assert_eq!(t.aggregate(100..200), brute_force(&m, 100, 200));
+    }
+
+    #[test]
+    fn mutable_access_keeps_aggregates() {
+        let mut rng = XorShiftRng::from_seed([6; 16]);
+        let m: BTreeMap<u32, u32> = (0..200).map(|k| (k, rng.gen_range(0, 1000))).collect();
+
+        let mut t: AugmentedTreapMap<_, _, All> = m.clone().into_iter().collect();
+        change_values(&mut t, &mut m.clone(), &mut rng);
+
+        let mut t: TreapMap<_, _, _, Arena<_, _, All>, All> = m.clone().into_iter().collect();
+        change_values(&mut t, &mut m.clone(), &mut rng);
+
+        // Copies share their nodes and summaries until one of them is changed
+        let mut t: TreapMap<_, _, _, Shared, All> = m.clone().into_iter().collect();
+        let u = t.clone();
+        change_values(&mut t, &mut m.clone(), &mut rng);
+        assert_eq!(u.aggregate(..), brute_force(&m, 0, 310));
+        let (store, root) = u.tree();
+        Node::check_summaries(store, root);
+    }
+
+    #[test]
+    fn aggregate_in_key_order() {
+        let t: AugmentedTreapMap<u32, (), Keys> = (0..200).rev().map(|k| (k, ())).collect();
+        assert_eq!(t.aggregate(..), (0..200).collect::<Vec<_>>());
+        assert_eq!(t.aggregate(50..=60), (50..=60).collect::<Vec<_>>());
+        assert_eq!(t.aggregate(300..), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,523 @@
+//! A treap map with nodes stored in a vector
+//!
+//! `ArenaTreapMap` is a `TreapMap` that keeps all of its nodes in an `Arena`: a single `Vec` in
+//! which nodes link to their children with `u32` indices instead of each node having its own
+//! `Box`. Slots of removed nodes are kept on a free list and reused by later insertions. Building
+//! the map needs fewer allocations and cloning the map clones one vector.
+//!
+//! Lookups are slower than with boxed nodes: each step down the tree turns an index into an
+//! address and checks the slot, and in the benchmarks `get` takes about 1.5 to 2 times as long as
+//! on a `TreapMap`. Keeping the nodes in one vector does not make up for this.
+//!
+//! Moving entries to another map, as `split_off`, `split_off_with`, `append`, `join` and
+//! `drain_range` do, copies the moved nodes into the other arena and takes time linear in their
+//! number. The mutable iterators in key order, `iter_ordered_mut`, `values_mut` and `range_mut`,
+//! borrow all of their entries when they are created, which takes time and memory linear in the
+//! number of entries they visit.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+use std::num::NonZeroU32;
+use std::slice;
+use std::vec;
+
+use rand::prng::XorShiftRng;
+
+use aggregate::Aggregate;
+use map::TreapMap;
+use node::{Link, Node, Store};
+use storage::Storage;
+
+/// A map based on a randomized treap with its nodes stored in an `Arena`.
+///
+/// Compared to a `TreapMap` with boxed nodes, building and cloning the map take fewer
+/// allocations, but lookups are slower, and `split_off`, `append`, `join` and `drain_range` take
+/// O(n) time instead of O(log n) because they copy nodes between arenas. The mutable iterators in
+/// key order also take O(n) time and memory to create. See the module documentation.
+///
+/// ```
+/// let mut t = treap::ArenaTreapMap::with_capacity(3);
+/// t.extend(vec![(2, "b"), (1, "a"), (3, "c")]);
+/// assert_eq!(t.first_key_value(), Some((&1, &"a")));
+/// let u = t.clone();
+/// assert_eq!(u, t);
+/// ```
+pub type ArenaTreapMap<K, V, P = XorShiftRng> = TreapMap<K, V, P, Arena<K, V>>;
+
+// The end of the free list
+const NIL: u32 = u32::MAX;
+
+/// Storage that keeps the nodes of a treap in the slots of a vector.
+pub struct Arena<K, V, A: Aggregate<K, V> = ()> {
+    slots: Vec<Slot<K, V, A>>,
+    // Index of the first free slot
+    free: u32,
+    // Number of occupied slots
+    len: usize,
+}
+
+enum Slot<K, V, A: Aggregate<K, V>> {
+    Occupied(Node<K, V, Arena<K, V, A>, A>),
+    // Index of the next free slot
+    Free(u32),
+}
+
+/// The index of an occupied slot plus one, so that a missing child takes no extra space.
+pub struct NodeIndex(NonZeroU32);
+
+/// A mutable iterator over the entries of an arena in slot order.
+pub struct IterMut<'a, K: 'a, V: 'a, A: 'a + Aggregate<K, V>> {
+    slots: slice::IterMut<'a, Slot<K, V, A>>,
+    remaining: usize,
+}
+
+impl NodeIndex {
+    fn new(slot: usize) -> NodeIndex {
+        NodeIndex(
+            u32::try_from(slot + 1)
+                .ok()
+                .and_then(NonZeroU32::new)
+                .expect("too many nodes for an arena"),
+        )
+    }
+
+    #[inline]
+    fn slot(&self) -> usize {
+        self.0.get() as usize - 1
+    }
+
+    #[inline]
+    fn copy(&self) -> NodeIndex {
+        NodeIndex(self.0)
+    }
+}
+
+impl<K, V> Arena<K, V> {
+    /// Create an empty arena.
+    pub fn new() -> Arena<K, V> {
+        Arena::default()
+    }
+
+    /// Create an empty arena with room for `capacity` nodes before the vector reallocates.
+    pub fn with_capacity(capacity: usize) -> Arena<K, V> {
+        Arena {
+            slots: Vec::with_capacity(capacity),
+            ..Arena::new()
+        }
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>> Arena<K, V, A> {
+    /// Number of slots in the arena, occupied or free.
+    #[cfg(test)]
+    pub fn slots(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[inline]
+    fn get(&self, slot: usize) -> &Node<K, V, Arena<K, V, A>, A> {
+        match self.slots[slot] {
+            Slot::Occupied(ref node) => node,
+            Slot::Free(_) => unreachable!("handle to a free slot"),
+        }
+    }
+
+    #[inline]
+    fn get_mut(&mut self, slot: usize) -> &mut Node<K, V, Arena<K, V, A>, A> {
+        match self.slots[slot] {
+            Slot::Occupied(ref mut node) => node,
+            Slot::Free(_) => unreachable!("handle to a free slot"),
+        }
+    }
+}
+
+impl<K: Ord, V> TreapMap<K, V, XorShiftRng, Arena<K, V>> {
+    /// Create an empty treap with room for `capacity` nodes before its arena reallocates.
+    ///
+    /// ```
+    /// let mut t = treap::ArenaTreapMap::with_capacity(100);
+    /// t.extend((0..100).map(|k| (k, k)));
+    /// assert_eq!(t.len(), 100);
+    /// ```
+    pub fn with_capacity(capacity: usize) -> ArenaTreapMap<K, V> {
+        TreapMap::new_in(Arena::with_capacity(capacity))
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>> Default for Arena<K, V, A> {
+    fn default() -> Arena<K, V, A> {
+        Arena {
+            slots: Vec::new(),
+            free: NIL,
+            len: 0,
+        }
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>> fmt::Debug for Arena<K, V, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Arena")
+            .field("len", &self.len)
+            .field("slots", &self.slots.len())
+            .finish()
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>> Storage<K, V, A> for Arena<K, V, A> {}
+
+impl<K, V, A: Aggregate<K, V>> Store<K, V, A> for Arena<K, V, A> {
+    type Handle = NodeIndex;
+    type IterMut<'a>
+        = IterMut<'a, K, V, A>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+    type OrderedIterMut<'a>
+        = vec::IntoIter<(&'a K, &'a mut V)>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+
+    #[inline]
+    fn node<'a>(&'a self, handle: &'a NodeIndex) -> &'a Node<K, V, Arena<K, V, A>, A> {
+        self.get(handle.slot())
+    }
+
+    #[inline]
+    fn node_mut<'a>(
+        &'a mut self,
+        handle: &'a mut NodeIndex,
+    ) -> &'a mut Node<K, V, Arena<K, V, A>, A> {
+        self.get_mut(handle.slot())
+    }
+
+    fn alloc(&mut self, node: Node<K, V, Arena<K, V, A>, A>) -> NodeIndex {
+        self.len += 1;
+        if self.free == NIL {
+            self.slots.push(Slot::Occupied(node));
+            return NodeIndex::new(self.slots.len() - 1);
+        }
+        let slot = self.free as usize;
+        match mem::replace(&mut self.slots[slot], Slot::Occupied(node)) {
+            Slot::Free(next) => self.free = next,
+            Slot::Occupied(_) => unreachable!("occupied slot on the free list"),
+        }
+        NodeIndex::new(slot)
+    }
+
+    fn take(&mut self, handle: NodeIndex) -> Node<K, V, Arena<K, V, A>, A> {
+        let slot = handle.slot();
+        match mem::replace(&mut self.slots[slot], Slot::Free(self.free)) {
+            Slot::Occupied(node) => {
+                self.free = slot as u32;
+                self.len -= 1;
+                node
+            }
+            Slot::Free(_) => unreachable!("handle to a free slot"),
+        }
+    }
+
+    fn drop_tree(&mut self, subtree: Link<K, V, Arena<K, V, A>, A>) {
+        // The size of the subtree cannot tell whether it is all that is left in the arena: a node
+        // handed over with a detached child still counts the nodes below that child
+        let mut stack: Vec<_> = subtree.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = self.take(node);
+            stack.extend(node.left);
+            stack.extend(node.right);
+        }
+        if self.len == 0 {
+            // Nothing is left, so the free list can be dropped with the slots
+            self.slots.clear();
+            self.free = NIL;
+        }
+    }
+
+    fn descend_mut<'a, F>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Arena<K, V, A>, A>,
+        mut step: F,
+    ) -> Option<&'a mut Node<K, V, Arena<K, V, A>, A>>
+    where
+        F: FnMut(&Node<K, V, Arena<K, V, A>, A>) -> Option<bool>,
+    {
+        let mut slot = subtree.as_ref()?.slot();
+        loop {
+            let node = self.get_mut(slot);
+            node.mark_changed();
+            let next = match step(node) {
+                None => break,
+                Some(true) => &node.left,
+                Some(false) => &node.right,
+            };
+            slot = next.as_ref()?.slot();
+        }
+        Some(self.get_mut(slot))
+    }
+
+    fn iter_mut<'a>(
+        &'a mut self,
+        _subtree: &'a mut Link<K, V, Arena<K, V, A>, A>,
+    ) -> IterMut<'a, K, V, A> {
+        // The entries are handed out in slot order, so any node can be changed before its parent
+        for slot in &mut self.slots {
+            if let Slot::Occupied(ref mut node) = *slot {
+                node.mark_changed();
+            }
+        }
+        IterMut {
+            remaining: self.len,
+            slots: self.slots.iter_mut(),
+        }
+    }
+
+    fn ordered_mut<'a>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Arena<K, V, A>, A>,
+        mut skip: usize,
+        len: usize,
+    ) -> vec::IntoIter<(&'a K, &'a mut V)> {
+        // Find the slots of the entries in key order. Then borrow the entries in slot order, in
+        // which they can be split off the vector one after another, and put them in key order.
+        // Every node passed on the way may be above an entry that is handed out.
+        let mut stack = Vec::new();
+        let mut cur = subtree.as_ref().map(NodeIndex::slot);
+        while let Some(slot) = cur {
+            self.get_mut(slot).mark_changed();
+            let node = self.get(slot);
+            let before = Node::size(self, &node.left);
+            if skip <= before {
+                stack.push(slot);
+                cur = node.left.as_ref().map(NodeIndex::slot);
+            } else {
+                skip -= before + 1;
+                cur = node.right.as_ref().map(NodeIndex::slot);
+            }
+        }
+        let mut order = Vec::with_capacity(len);
+        while order.len() < len {
+            let slot = match stack.pop() {
+                Some(slot) => slot,
+                None => break,
+            };
+            order.push((slot, order.len()));
+            let mut cur = self.get(slot).right.as_ref().map(NodeIndex::slot);
+            while let Some(slot) = cur {
+                stack.push(slot);
+                self.get_mut(slot).mark_changed();
+                cur = self.get(slot).left.as_ref().map(NodeIndex::slot);
+            }
+        }
+        order.sort_unstable();
+        let mut entries: Vec<_> = order.iter().map(|_| None).collect();
+        let mut slots = self.slots.iter_mut();
+        let mut next = 0;
+        for (slot, position) in order {
+            if let Some(&mut Slot::Occupied(ref mut node)) = slots.nth(slot - next) {
+                entries[position] = Some((&node.key, &mut node.value));
+            }
+            next = slot + 1;
+        }
+        entries
+            .into_iter()
+            .map(|entry| entry.expect("handle to a free slot"))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn adopt(
+        &mut self,
+        other: &mut Arena<K, V, A>,
+        subtree: Link<K, V, Arena<K, V, A>, A>,
+    ) -> Link<K, V, Arena<K, V, A>, A> {
+        if self.len == 0 && Node::size(other, &subtree) == other.len {
+            mem::swap(self, other);
+            return subtree;
+        }
+        // Move the nodes in pre-order, each into the link left for it in its parent's copy
+        let mut root = None;
+        let mut stack: Vec<_> = subtree.map(|node| (node, None)).into_iter().collect();
+        while let Some((node, parent)) = stack.pop() {
+            let mut node = other.take(node);
+            let (left, right) = (node.left.take(), node.right.take());
+            let moved = self.alloc(node);
+            let slot = moved.slot();
+            match parent {
+                None => root = Some(moved),
+                Some((parent, true)) => self.get_mut(parent).left = Some(moved),
+                Some((parent, false)) => self.get_mut(parent).right = Some(moved),
+            }
+            stack.extend(right.map(|right| (right, Some((slot, false)))));
+            stack.extend(left.map(|left| (left, Some((slot, true)))));
+        }
+        root
+    }
+
+    fn clone_tree(
+        &self,
+        subtree: &Link<K, V, Arena<K, V, A>, A>,
+    ) -> (Arena<K, V, A>, Link<K, V, Arena<K, V, A>, A>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let slots = self
+            .slots
+            .iter()
+            .map(|slot| match *slot {
+                Slot::Occupied(ref node) => {
+                    let mut copy = node.copy_detached();
+                    copy.left = node.left.as_ref().map(NodeIndex::copy);
+                    copy.right = node.right.as_ref().map(NodeIndex::copy);
+                    Slot::Occupied(copy)
+                }
+                Slot::Free(next) => Slot::Free(next),
+            })
+            .collect();
+        let arena = Arena {
+            slots,
+            free: self.free,
+            len: self.len,
+        };
+        (arena, subtree.as_ref().map(NodeIndex::copy))
+    }
+}
+
+impl<'a, K, V, A: Aggregate<K, V>> Iterator for IterMut<'a, K, V, A> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        for slot in &mut self.slots {
+            if let Slot::Occupied(ref mut node) = *slot {
+                self.remaining -= 1;
+                return Some((&node.key, &mut node.value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V, A: Aggregate<K, V>> ExactSizeIterator for IterMut<'a, K, V, A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::ArenaTreapMap;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn matches_btree_map() {
+        let mut rng = XorShiftRng::from_seed([7; 16]);
+        let mut t = ArenaTreapMap::default();
+        let mut m = BTreeMap::new();
+        for _ in 0..5000 {
+            let k = rng.gen_range(0, 500);
+            if rng.gen() {
+                assert_eq!(t.insert(k, k * 2), m.insert(k, k * 2));
+            } else {
+                assert_eq!(t.remove(&k), m.remove(&k));
+            }
+        }
+        t.check_invariants();
+        assert_eq!(t.len(), m.len());
+        assert!(t.iter_ordered().eq(m.iter()));
+        assert!(t.iter_ordered().rev().eq(m.iter().rev()));
+        assert!(t.range(100..=200).eq(m.range(100..=200)));
+        assert_eq!(t.range(100..=200).len(), m.range(100..=200).count());
+        for (k, v) in t.range_mut(100..=200) {
+            *v += k;
+        }
+        for (k, v) in m.range_mut(100..=200) {
+            *v += k;
+        }
+        assert!(t.iter_ordered_mut().map(|(k, v)| (*k, *v)).eq(m.clone()));
+        let mut entries: Vec<_> = (&mut t).into_iter().map(|(k, v)| (*k, *v)).collect();
+        entries.sort();
+        assert!(entries.into_iter().eq(m.clone()));
+        // Removed slots are reused
+        assert!(t.storage().slots() <= 500);
+    }
+
+    #[test]
+    fn clone_and_clear() {
+        let mut t: ArenaTreapMap<_, _> = (0..100).map(|k| (k, k)).collect();
+        let u = t.clone();
+        t.clear();
+        assert!(t.is_empty());
+        assert_eq!(t.first_key_value(), None);
+        assert_eq!(u.len(), 100);
+        assert_eq!(u[&42], 42);
+        t.insert(1, 1);
+        t.check_invariants();
+        assert_eq!(format!("{:?}", t), "{1: 1}");
+    }
+
+    #[test]
+    fn entries_and_order_statistics() {
+        let mut t = ArenaTreapMap::default();
+        for word in "a b a c a b".split(' ') {
+            *t.entry(word).or_insert(0) += 1;
+        }
+        t["c"] += 10;
+        assert_eq!(t.rank(&"b"), 1);
+        assert_eq!(t.select(2), Some((&"c", &11)));
+        assert_eq!(t.floor(&"bb"), Some((&"b", &2)));
+        assert_eq!(t.pop_first(), Some(("a", 3)));
+        assert_eq!(t.pop_last(), Some(("c", 11)));
+        t.check_invariants();
+        assert_eq!(t.into_iter_ordered().collect::<Vec<_>>(), vec![("b", 2)]);
+    }
+
+    #[test]
+    fn move_between_arenas() {
+        let mut t: ArenaTreapMap<_, _> = (0..100).map(|k| (k, k)).collect();
+        let mut u = t.split_off(&50);
+        t.check_invariants();
+        u.check_invariants();
+        assert!(t.keys().cloned().eq(0..50));
+        assert!(u.keys().cloned().eq(50..100));
+        let drained: Vec<_> = u.drain_range(60..70).map(|(k, _)| k).collect();
+        assert_eq!(drained, (60..70).collect::<Vec<_>>());
+        t.append(&mut u);
+        t.check_invariants();
+        assert!(u.is_empty());
+        assert_eq!(t.len(), 90);
+        // The appended nodes take the slots freed by `split_off`
+        assert_eq!(t.storage().slots(), 100);
+        t.retain(|k, _| k % 2 == 0);
+        t.check_invariants();
+        assert!(t.keys().all(|k| k % 2 == 0));
+    }
+
+    #[test]
+    fn drop_owning_iterators_partway() {
+        let t: ArenaTreapMap<_, _> = (0..100).map(|k| (k, k)).collect();
+        drop(t.clone().into_iter_ordered());
+        let mut iter = t.clone().into_iter_ordered();
+        assert_eq!(iter.nth(40), Some((40, 40)));
+        drop(iter);
+        let mut keys = t.clone().into_keys();
+        assert_eq!(keys.next(), Some(0));
+        drop(keys);
+        let mut values = t.clone().into_values();
+        assert_eq!(values.next(), Some(0));
+        drop(values);
+
+        let mut u = t.clone();
+        drop(u.drain_range(10..50));
+        u.check_invariants();
+        assert_eq!(u.len(), 60);
+        let mut drain = u.drain_range(60..90);
+        assert_eq!(drain.next(), Some((60, 60)));
+        drop(drain);
+        u.check_invariants();
+        assert!(u.keys().cloned().eq((0..10).chain(50..60).chain(90..100)));
+    }
+}
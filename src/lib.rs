@@ -6,12 +6,31 @@
 //!
 //! This implementation is randomized meaning that the priorities are assigned at random. The treap
 //! has an expected depth of O(log n).
+//!
+//! By default the priorities are drawn from a random number generator, but a treap can be given
+//! any source of priorities. See the `priority` module.
+//!
+//! The nodes of a treap are kept in a `Storage`, by default one `Box` per node. `ArenaTreapMap`
+//! keeps them in a single vector instead, and `PersistentTreapMap` shares them between versions.
+//! See the `storage` module.
+//!
+//! A treap can also cache a summary of every subtree, computed with a user-defined `Aggregate`,
+//! so that aggregates over key ranges take O(log n) time. See the `aggregate` module.
 
 extern crate rand;
 
-pub use map::TreapMap;
+pub use aggregate::{Aggregate, AugmentedTreapMap};
+pub use arena::ArenaTreapMap;
+pub use map::{TreapMap, UnsortedError};
+pub use persistent::{PersistentTreapMap, SharedTreapMap, Snapshot};
+pub use priority::PrioritySource;
 pub use set::TreapSet;
 
+pub mod aggregate;
+pub mod arena;
 pub mod map;
 mod node;
+pub mod persistent;
+pub mod priority;
 pub mod set;
+pub mod storage;
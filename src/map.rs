@@ -1,49 +1,262 @@
 use rand;
 
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::default::Default;
-use std::iter::{FromIterator, IntoIterator};
-use std::ops::{Index, IndexMut};
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::{FromIterator, FusedIterator, IntoIterator};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Bound, Index, IndexMut, RangeBounds};
 
 use rand::FromEntropy;
 use rand::prng::XorShiftRng;
 
-use node::Node;
+use aggregate::Aggregate;
+use node::{Link, Node, Path, Unlink};
+use priority::{HashedPriorities, PrioritySource};
+use storage::{Boxed, Storage};
 
 /// A map based on a randomized treap.
-#[derive(Debug, Clone)]
-pub struct TreapMap<K, V, Rng = rand::XorShiftRng> {
-    root: Option<Box<Node<K, V>>>,
+///
+/// The nodes are kept in a `Storage`, by default one `Box` per node. See the `storage` module.
+/// Each node can also keep a summary of its subtree, computed with the `Aggregate` `A`, which
+/// `aggregate` combines over a range of keys. By default no summaries are kept. See the
+/// `aggregate` module.
+///
+/// `snapshot` takes a read-only view of the treap that later changes do not affect. With the
+/// default storage it copies the entries in O(n); with `Shared` storage, as in a
+/// `SharedTreapMap`, the snapshot shares the nodes of the treap and is taken in O(1). See the
+/// `persistent` module.
+pub struct TreapMap<K, V, P = XorShiftRng, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    root: Link<K, V, S, A>,
+    store: S,
     size: usize,
-    rng: Rng,
+    priorities: P,
+}
+
+/// A view into a single entry in a treap, which may either be vacant or occupied.
+pub enum Entry<
+    'a,
+    K: 'a,
+    V: 'a,
+    P: 'a = XorShiftRng,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, V, P, S, A>),
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, V, P, S, A>),
+}
+
+/// A view into a vacant entry in a treap.
+pub struct VacantEntry<
+    'a,
+    K: 'a,
+    V: 'a,
+    P: 'a = XorShiftRng,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    key: K,
+    path: Path<'a, K, V, S, A>,
+    size: &'a mut usize,
+    priorities: &'a mut P,
+}
+
+/// A view into an occupied entry in a treap.
+pub struct OccupiedEntry<
+    'a,
+    K: 'a,
+    V: 'a,
+    P: 'a = XorShiftRng,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    path: Path<'a, K, V, S, A>,
+    size: &'a mut usize,
+    marker: PhantomData<&'a mut P>,
 }
 
 /// An iterator over a treap's entries.
-pub struct Iter<'a, K: 'a, V: 'a> {
-    nodes: Vec<&'a Node<K, V>>,
+pub struct Iter<'a, K: 'a, V: 'a, S: 'a + Storage<K, V, A> = Boxed, A: 'a + Aggregate<K, V> = ()> {
+    store: &'a S,
+    nodes: Vec<&'a Node<K, V, S, A>>,
+    remaining: usize,
 }
 
 /// A mutable iterator over a treap's entries.
-pub struct IterMut<'a, K: 'a, V: 'a> {
-    nodes: Vec<&'a mut Node<K, V>>,
+pub struct IterMut<'a, K: 'a, V: 'a, S: 'a + Storage<K, V, A> = Boxed, A: 'a + Aggregate<K, V> = ()>
+{
+    iter: S::IterMut<'a>,
 }
 
 /// An owning iterator over a treap's entries.
-pub struct IntoIter<K, V> {
-    nodes: Vec<Node<K, V>>,
+pub struct IntoIter<K, V, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    store: S,
+    nodes: Vec<S::Handle>,
+    remaining: usize,
 }
 
-enum Traversal<T> {
-    // Traverse left subtree before emitting value at node
-    Left(T),
-    // Emit value at node and continue with right subtree
-    Right(T),
+/// An iterator over a sub-range of a treap's entries in key order.
+pub struct Range<'a, K: 'a, V: 'a, S: 'a + Storage<K, V, A> = Boxed, A: 'a + Aggregate<K, V> = ()> {
+    iter: OrderedIter<'a, K, V, S, A>,
+}
+
+/// A mutable iterator over a sub-range of a treap's entries in key order.
+pub struct RangeMut<
+    'a,
+    K: 'a,
+    V: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    iter: OrderedIterMut<'a, K, V, S, A>,
+}
+
+/// An owning iterator over the entries removed from a treap by `drain_range`, in key order.
+pub struct DrainRange<K, V, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    iter: IntoOrderedIter<K, V, S, A>,
 }
 
 /// An iterator over a treap's entries in key order.
-pub struct OrderedIter<'a, K: 'a, V: 'a> {
-    nodes: Vec<Traversal<&'a Node<K, V>>>,
+pub struct OrderedIter<
+    'a,
+    K: 'a,
+    V: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    store: &'a S,
+    // Nodes to emit from the front, each to be followed by its right subtree
+    front: Vec<&'a Node<K, V, S, A>>,
+    // Nodes to emit from the back, each to be followed by its left subtree
+    back: Vec<&'a Node<K, V, S, A>>,
+    remaining: usize,
+}
+
+/// A mutable iterator over a treap's entries in key order.
+pub struct OrderedIterMut<
+    'a,
+    K: 'a,
+    V: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    iter: S::OrderedIterMut<'a>,
+}
+
+/// An owning iterator over a treap's entries in key order.
+pub struct IntoOrderedIter<K, V, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    store: S,
+    nodes: Unlink<K, V, S, A>,
+}
+
+/// An iterator that removes and yields the entries of a treap for which a predicate holds, in
+/// key order. Created by `extract_if`.
+pub struct ExtractIf<
+    'a,
+    K: 'a + Ord,
+    V: 'a,
+    F,
+    P: 'a = XorShiftRng,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> where
+    F: FnMut(&K, &mut V) -> bool,
+{
+    extraction: Extraction<'a, K, V, P, S, A>,
+    pred: F,
+}
+
+// The treap is taken apart while entries are extracted and rebuilt from the kept nodes when
+// this is dropped, so that removing many entries costs linear time in total.
+pub(crate) struct Extraction<
+    'a,
+    K: 'a + Ord,
+    V: 'a,
+    P: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    map: &'a mut TreapMap<K, V, P, S, A>,
+    nodes: Unlink<K, V, S, A>,
+    kept: Vec<S::Handle>,
+}
+
+/// A formatter that shows the structure of a treap. Created by `debug_tree`.
+pub struct DebugTree<
+    'a,
+    K: 'a,
+    V: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    store: &'a S,
+    root: Option<&'a Node<K, V, S, A>>,
+}
+
+/// An iterator over a treap's keys in sorted order.
+pub struct Keys<'a, K: 'a, V: 'a, S: 'a + Storage<K, V, A> = Boxed, A: 'a + Aggregate<K, V> = ()> {
+    iter: OrderedIter<'a, K, V, S, A>,
+}
+
+/// An iterator over a treap's values in key order.
+pub struct Values<'a, K: 'a, V: 'a, S: 'a + Storage<K, V, A> = Boxed, A: 'a + Aggregate<K, V> = ()>
+{
+    iter: OrderedIter<'a, K, V, S, A>,
+}
+
+/// A mutable iterator over a treap's values in key order.
+pub struct ValuesMut<
+    'a,
+    K: 'a,
+    V: 'a,
+    S: 'a + Storage<K, V, A> = Boxed,
+    A: 'a + Aggregate<K, V> = (),
+> {
+    iter: OrderedIterMut<'a, K, V, S, A>,
+}
+
+/// An owning iterator over a treap's keys in sorted order.
+pub struct IntoKeys<K, V, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    iter: IntoOrderedIter<K, V, S, A>,
+}
+
+/// An owning iterator over a treap's values in key order.
+pub struct IntoValues<K, V, S: Storage<K, V, A> = Boxed, A: Aggregate<K, V> = ()> {
+    iter: IntoOrderedIter<K, V, S, A>,
 }
 
+/// The error returned when building a treap from input that is not sorted by key or contains
+/// duplicate keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsortedError {
+    index: usize,
+}
+
+impl UnsortedError {
+    /// The position in the input of the first key that is not greater than the key before it.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl fmt::Display for UnsortedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "input is not sorted and deduplicated: key at index {} is out of order",
+            self.index
+        )
+    }
+}
+
+impl Error for UnsortedError {}
+
 impl<K: Ord, V> TreapMap<K, V, XorShiftRng> {
     /// Create an empty treap with the default random number generator. The
     /// XorShift random number generator is used by default since it is fast,
@@ -57,15 +270,116 @@ impl<K: Ord, V> TreapMap<K, V, XorShiftRng> {
     /// }
     /// ```
     pub fn new() -> TreapMap<K, V, XorShiftRng> {
-        TreapMap {
-            root: None,
-            size: 0,
-            rng: XorShiftRng::from_entropy(),
+        TreapMap::new_in(Boxed)
+    }
+
+    /// Build a treap in linear time from entries sorted by key without duplicate keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the keys are not strictly increasing. Use `try_from_sorted_iter` to handle such
+    /// input without panicking.
+    ///
+    /// ```
+    /// let t = treap::TreapMap::from_sorted_iter((0..100).map(|k| (k, k * 2)));
+    /// assert_eq!(t.len(), 100);
+    /// assert_eq!(t.get(&21), Some(&42));
+    /// ```
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> TreapMap<K, V> {
+        match TreapMap::try_from_sorted_iter(iter) {
+            Ok(treap) => treap,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Build a treap in linear time from a vector of entries sorted by key without duplicate
+    /// keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the keys are not strictly increasing.
+    ///
+    /// ```
+    /// let t = treap::TreapMap::from_sorted_vec(vec![(1, "a"), (2, "b"), (3, "c")]);
+    /// assert_eq!(t.get(&2), Some(&"b"));
+    /// ```
+    pub fn from_sorted_vec(entries: Vec<(K, V)>) -> TreapMap<K, V> {
+        TreapMap::from_sorted_iter(entries)
+    }
+
+    /// Build a treap in linear time from entries sorted by key without duplicate keys, or return
+    /// an error if the keys are not strictly increasing.
+    ///
+    /// ```
+    /// let t = treap::TreapMap::try_from_sorted_iter(vec![(1, "a"), (2, "b")]);
+    /// assert_eq!(t.map(|t| t.len()), Ok(2));
+    ///
+    /// let err = treap::TreapMap::try_from_sorted_iter(vec![(1, "a"), (3, "b"), (2, "c")]);
+    /// assert_eq!(err.map(|t| t.len()).unwrap_err().index(), 2);
+    /// ```
+    pub fn try_from_sorted_iter<I: IntoIterator<Item = (K, V)>>(
+        iter: I,
+    ) -> Result<TreapMap<K, V>, UnsortedError> {
+        TreapMap::new().with_sorted(iter)
+    }
+}
+
+impl<K: Ord, V, S: Storage<K, V, A>, A: Aggregate<K, V>> TreapMap<K, V, XorShiftRng, S, A> {
+    /// Create an empty treap that keeps its nodes in the given storage, with the default random
+    /// number generator.
+    ///
+    /// ```
+    /// use treap::arena::Arena;
+    ///
+    /// let mut t = treap::TreapMap::new_in(Arena::new());
+    /// t.insert(5, "yellow");
+    /// assert_eq!(t.get(&5), Some(&"yellow"));
+    /// ```
+    pub fn new_in(storage: S) -> TreapMap<K, V, XorShiftRng, S, A> {
+        TreapMap::new_with_priorities_in(XorShiftRng::from_entropy(), storage)
+    }
+
+    /// Build a treap in linear time from entries sorted by key without duplicate keys, keeping its
+    /// nodes in the given storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the keys are not strictly increasing.
+    ///
+    /// ```
+    /// use treap::arena::Arena;
+    ///
+    /// let t = treap::TreapMap::from_sorted_iter_in((0..100).map(|k| (k, k * 2)), Arena::new());
+    /// assert_eq!(t.get(&21), Some(&42));
+    /// ```
+    pub fn from_sorted_iter_in<I: IntoIterator<Item = (K, V)>>(
+        iter: I,
+        storage: S,
+    ) -> TreapMap<K, V, XorShiftRng, S, A> {
+        match TreapMap::try_from_sorted_iter_in(iter, storage) {
+            Ok(treap) => treap,
+            Err(err) => panic!("{}", err),
         }
     }
+
+    /// Build a treap in linear time from entries sorted by key without duplicate keys, keeping its
+    /// nodes in the given storage, or return an error if the keys are not strictly increasing.
+    ///
+    /// ```
+    /// use treap::arena::Arena;
+    ///
+    /// let err = treap::TreapMap::try_from_sorted_iter_in(vec![(2, "a"), (1, "b")], Arena::new());
+    /// assert_eq!(err.map(|t| t.len()).unwrap_err().index(), 1);
+    /// ```
+    pub fn try_from_sorted_iter_in<I: IntoIterator<Item = (K, V)>>(
+        iter: I,
+        storage: S,
+    ) -> Result<TreapMap<K, V, XorShiftRng, S, A>, UnsortedError> {
+        TreapMap::new_in(storage).with_sorted(iter)
+    }
 }
 
-impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
+impl<K: Ord, V, P: PrioritySource<K>> TreapMap<K, V, P> {
     /// Create an empty treap with a given random number generator.
     ///
     /// ```
@@ -77,14 +391,90 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// t.insert(5, "yellow");
     ///# }
     /// ```
-    pub fn new_with_rng(rng: Rng) -> TreapMap<K, V, Rng> {
+    pub fn new_with_rng(rng: P) -> TreapMap<K, V, P>
+    where
+        P: rand::Rng,
+    {
+        TreapMap::new_with_priorities(rng)
+    }
+
+    /// Create an empty treap that draws the priorities of new keys from the given source.
+    ///
+    /// ```
+    /// use treap::priority;
+    ///
+    /// // A treap with priorities that decrease with the key is a right-leaning path
+    /// let mut t = treap::TreapMap::new_with_priorities(priority::from_fn(|k: &u64| !k));
+    /// t.insert(1, "a");
+    /// t.insert(2, "b");
+    /// assert_eq!(t.first_key_value(), Some((&1, &"a")));
+    /// ```
+    pub fn new_with_priorities(priorities: P) -> TreapMap<K, V, P> {
+        TreapMap::new_with_priorities_in(priorities, Boxed)
+    }
+
+    /// Build a treap in linear time from entries sorted by key without duplicate keys, drawing
+    /// the priorities from the given source. Returns an error if the keys are not strictly
+    /// increasing.
+    ///
+    /// ```
+    /// use treap::priority::HashedPriorities;
+    ///
+    /// let entries = (0..100).map(|k| (k, k));
+    /// let t = treap::TreapMap::try_from_sorted_iter_with_priorities(entries, HashedPriorities::new(1));
+    /// assert_eq!(t.map(|t| t.len()), Ok(100));
+    /// ```
+    pub fn try_from_sorted_iter_with_priorities<I: IntoIterator<Item = (K, V)>>(
+        iter: I,
+        priorities: P,
+    ) -> Result<TreapMap<K, V, P>, UnsortedError> {
+        TreapMap::new_with_priorities(priorities).with_sorted(iter)
+    }
+}
+
+impl<K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    TreapMap<K, V, P, S, A>
+{
+    /// Create an empty treap that keeps its nodes in the given storage and draws the priorities of
+    /// new keys from the given source.
+    ///
+    /// ```
+    /// use treap::arena::Arena;
+    /// use treap::priority::HashedPriorities;
+    ///
+    /// let mut t = treap::TreapMap::new_with_priorities_in(HashedPriorities::new(1), Arena::new());
+    /// t.insert("a", 1);
+    /// assert_eq!(t.len(), 1);
+    /// ```
+    pub fn new_with_priorities_in(priorities: P, storage: S) -> TreapMap<K, V, P, S, A> {
         TreapMap {
             root: None,
+            store: storage,
             size: 0,
-            rng,
+            priorities,
         }
     }
 
+    // Fill an empty treap from sorted entries with freshly drawn priorities.
+    fn with_sorted<I: IntoIterator<Item = (K, V)>>(
+        mut self,
+        iter: I,
+    ) -> Result<TreapMap<K, V, P, S, A>, UnsortedError> {
+        let store = &mut self.store;
+        let priorities = &mut self.priorities;
+        let nodes: Vec<_> = iter
+            .into_iter()
+            .map(|(key, value)| {
+                let priority = priorities.priority(&key);
+                store.alloc(Node::new(key, value, priority))
+            })
+            .collect();
+        self.root =
+            Node::from_sorted_nodes(store, nodes).map_err(|index| UnsortedError { index })?;
+        self.size = Node::size(&self.store, &self.root);
+        Ok(self)
+    }
+
     /// Return the number of elements in the treap.
     ///
     /// ```
@@ -118,7 +508,7 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// assert!(t.is_empty());
     /// ```
     pub fn clear(&mut self) {
-        self.root.take();
+        self.store.drop_tree(self.root.take());
         self.size = 0;
     }
 
@@ -132,8 +522,27 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// assert_eq!(t.get(&5), Some(&"yellow"));
     /// assert_eq!(t.get(&10), None);
     /// ```
-    pub fn get(&self, key: &K) -> Option<&V> {
-        self.root.as_ref().and_then(|n| n.get(key))
+    pub fn get<Q: ?Sized + Ord>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    /// Borrow the stored key and the value corresponding to the given key if it exists in the
+    /// treap.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.insert(5, "yellow");
+    /// assert_eq!(t.get_key_value(&5), Some((&5, &"yellow")));
+    /// assert_eq!(t.get_key_value(&10), None);
+    /// ```
+    pub fn get_key_value<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::get(&self.store, Node::at(&self.store, &self.root), key).map(|n| (&n.key, &n.value))
     }
 
     /// Return a mutable reference to the value corresponding to the given key if it exists in the treap.
@@ -147,8 +556,18 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// }
     /// assert_eq!(t.get(&5), Some(&"blue"));
     /// ```
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        self.root.as_mut().and_then(|n| n.get_mut(key))
+    pub fn get_mut<Q: ?Sized + Ord>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        Node::refresh(&mut self.store, &mut self.root);
+        self.store
+            .descend_mut(&mut self.root, |node| match key.cmp(node.key.borrow()) {
+                Ordering::Equal => None,
+                Ordering::Less => Some(true),
+                Ordering::Greater => Some(false),
+            })
+            .map(|n| &mut n.value)
     }
 
     /// Returns true if the key is present in the treap.
@@ -159,7 +578,10 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// assert_eq!(t.contains_key(&5), true);
     /// assert_eq!(t.contains_key(&8), false);
     /// ```
-    pub fn contains_key(&self, key: &K) -> bool {
+    pub fn contains_key<Q: ?Sized + Ord>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
         self.get(key).is_some()
     }
 
@@ -172,12 +594,15 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// assert_eq!(t.insert(5, "blue"), Some("yellow"));
     /// ```
     pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        let priority = self.rng.gen();
-        let res = Node::insert_or_replace(&mut self.root, Node::new(key, value, priority));
-        if res.is_none() {
-            self.size += 1;
+        Node::refresh(&mut self.store, &mut self.root);
+        let mut path = Path::search(&mut self.store, &mut self.root, &key);
+        if let Some(node) = path.end_mut() {
+            return Some(mem::replace(&mut node.value, value));
         }
-        res
+        let priority = self.priorities.priority(&key);
+        path.insert(Node::new(key, value, priority));
+        self.size += 1;
+        None
     }
 
     /// Remove the given key from the treap and return the value associated with it if any.
@@ -188,272 +613,1648 @@ impl<K: Ord, V, Rng: rand::Rng> TreapMap<K, V, Rng> {
     /// assert_eq!(t.remove(&5), Some("blue"));
     /// assert_eq!(t.remove(&10), None);
     /// ```
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        let res = Node::remove(&mut self.root, key);
-        if res.is_some() {
-            self.size -= 1;
-        }
-        res
+    pub fn remove<Q: ?Sized + Ord>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.remove_entry(key).map(|(_, v)| v)
     }
 
-    /// Returns an iterator over keys and values in the treap that gives the keys in sorted order.
+    /// Remove the given key from the treap and return the stored key and value if any.
     ///
     /// ```
     /// let mut t = treap::TreapMap::new();
-    /// t.extend((1..10).map(|x| (x, "a")));
+    /// t.insert(5, "blue");
+    /// assert_eq!(t.remove_entry(&5), Some((5, "blue")));
+    /// assert_eq!(t.remove_entry(&5), None);
+    /// ```
+    pub fn remove_entry<Q: ?Sized + Ord>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::refresh(&mut self.store, &mut self.root);
+        let node = Path::search(&mut self.store, &mut self.root, key).remove()?;
+        self.size -= 1;
+        Some((node.key, node.value))
+    }
+
+    /// Get the entry for the given key for in-place manipulation. The key is searched for once;
+    /// reading, changing, inserting or removing the entry afterwards does not search again.
     ///
-    /// let v: Vec<i32> = t.iter_ordered().map(|(&k, _)| k).collect();
-    /// assert_eq!(v, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
     /// ```
-    pub fn iter_ordered(&self) -> OrderedIter<K, V> {
-        OrderedIter {
-            nodes: match self.root {
-                None => Vec::new(),
-                Some(ref n) => vec![Traversal::Left(&**n)],
-            },
+    /// let mut t = treap::TreapMap::new();
+    /// for word in "a b a c a b".split(' ') {
+    ///     *t.entry(word).or_insert(0) += 1;
+    /// }
+    /// assert_eq!(t.get(&"a"), Some(&3));
+    /// assert_eq!(t.get(&"b"), Some(&2));
+    /// assert_eq!(t.get(&"c"), Some(&1));
+    /// ```
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, P, S, A> {
+        Node::refresh(&mut self.store, &mut self.root);
+        let path = Path::search(&mut self.store, &mut self.root, &key);
+        if path.end().is_some() {
+            Entry::Occupied(OccupiedEntry {
+                path,
+                size: &mut self.size,
+                marker: PhantomData,
+            })
+        } else {
+            Entry::Vacant(VacantEntry {
+                key,
+                path,
+                size: &mut self.size,
+                priorities: &mut self.priorities,
+            })
         }
     }
-}
-
-impl<K: Ord + Clone, Rng: rand::Rng> TreapMap<K, (), Rng> {
-    pub fn delete_range(&mut self, from: K, to: K, output: &mut Vec<K>) {
-        let max_prio = ::std::f64::MAX;
-        let mut root: Option<Box<Node<K, ()>>> = self.root.take();
-        let res = Node::insert_or_replace(&mut root, Node::new(from.clone(), (), max_prio));
-        let mut root = root.unwrap();
-
-        let (left, right) = (root.left.take(), root.right.take());
-        if res.is_some() {
-            output.push(root.key)
-        };
-
-        let mut root = right;
-        let res = Node::insert_or_replace(&mut root, Node::new(to.clone(), (), max_prio));
-        let mut root = root.unwrap();
-        let (mid, mut right) = (root.left.take(), root.right.take());
-        if res.is_some() {
-            let x = Node::new(to.clone(), (), self.rng.gen());
-            Node::insert_or_replace(&mut right, x);
-        }
-
-        *root = Node::new(from.clone(), (), max_prio);
-        root.left = left;
-        root.right = right;
-        let mut root = Some(root);
-        let res = Node::remove(&mut root, &from);
-        assert!(res.is_some());
-
-        let iter = IntoIter {
-            nodes: match mid {
-                None => Vec::new(),
-                Some(n) => vec![*n],
-            },
-        };
 
-        output.extend(iter.map(|(k, _)| k));
-
-        self.root = root;
-        self.size -= output.len();
+    /// Borrow the entry with the smallest key in the treap.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// assert_eq!(t.first_key_value(), None);
+    /// t.extend(vec![(5, "a"), (2, "b"), (8, "c")]);
+    /// assert_eq!(t.first_key_value(), Some((&2, &"b")));
+    /// ```
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        Node::at(&self.store, &self.root).map(|n| {
+            let n = Node::leftmost(&self.store, n);
+            (&n.key, &n.value)
+        })
     }
-}
 
-impl<K: Ord, V, Rng: rand::Rng> Extend<(K, V)> for TreapMap<K, V, Rng> {
-    #[inline]
-    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
-        for (k, v) in iter {
-            self.insert(k, v);
-        }
+    /// Borrow the entry with the largest key in the treap.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(5, "a"), (2, "b"), (8, "c")]);
+    /// assert_eq!(t.last_key_value(), Some((&8, &"c")));
+    /// ```
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        Node::at(&self.store, &self.root).map(|n| {
+            let n = Node::rightmost(&self.store, n);
+            (&n.key, &n.value)
+        })
     }
-}
 
-impl<K: Ord, V> FromIterator<(K, V)> for TreapMap<K, V> {
-    #[inline]
-    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> TreapMap<K, V> {
-        let mut treap = TreapMap::new();
-        treap.extend(iter);
-        treap
+    /// Borrow the entry with the greatest key less than or equal to the given key.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.floor(&25), Some((&20, &"b")));
+    /// assert_eq!(t.floor(&20), Some((&20, &"b")));
+    /// assert_eq!(t.floor(&5), None);
+    /// ```
+    pub fn floor<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::last_in_prefix(&self.store, &self.root, |k| k.borrow() <= key)
+            .map(|n| (&n.key, &n.value))
     }
-}
 
-impl<K: Ord, V> Default for TreapMap<K, V> {
-    fn default() -> TreapMap<K, V> {
-        TreapMap::new()
+    /// Borrow the entry with the least key greater than or equal to the given key.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.ceiling(&15), Some((&20, &"b")));
+    /// assert_eq!(t.ceiling(&20), Some((&20, &"b")));
+    /// assert_eq!(t.ceiling(&35), None);
+    /// ```
+    pub fn ceiling<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::first_after_prefix(&self.store, &self.root, |k| k.borrow() < key)
+            .map(|n| (&n.key, &n.value))
     }
-}
-
-/// Return an iterator that moves keys and values out of treap. The order is arbitrary.
-///
-/// ```
-/// let mut t = treap::TreapMap::new();
-/// t.extend(vec![(1, "red"), (2, "blue"), (3, "green")].into_iter());
-///
-/// // Print keys and values in arbitrary order.
-/// for (k, v) in t {
-///     println!("{}: {}", k, v);
-/// }
-/// ```
-impl<K: Ord, V, Rng: rand::Rng> IntoIterator for TreapMap<K, V, Rng> {
-    type Item = (K, V);
-    type IntoIter = IntoIter<K, V>;
 
-    fn into_iter(self) -> IntoIter<K, V> {
-        IntoIter {
-            nodes: match self.root {
-                None => Vec::new(),
-                Some(n) => vec![*n],
-            },
-        }
+    /// Borrow the entry with the greatest key strictly less than the given key.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.lower(&20), Some((&10, &"a")));
+    /// assert_eq!(t.lower(&10), None);
+    /// ```
+    pub fn lower<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::last_in_prefix(&self.store, &self.root, |k| k.borrow() < key)
+            .map(|n| (&n.key, &n.value))
     }
-}
-
-/// Return an iterator over keys and values in the treap. The order is arbitrary.
-///
-/// ```
-/// let mut t = treap::TreapMap::new();
-/// t.extend(vec![(1, 200), (2, 120), (3, 330)].into_iter());
-///
-/// let sum = (&t).into_iter().fold(0, |s, (&k, &v)| s + k + v);
-/// assert_eq!(sum, 656);
-/// ```
-impl<'a, K: Ord, V, Rng: rand::Rng> IntoIterator for &'a TreapMap<K, V, Rng> {
-    type Item = (&'a K, &'a V);
-    type IntoIter = Iter<'a, K, V>;
 
-    fn into_iter(self) -> Iter<'a, K, V> {
-        Iter {
-            nodes: match self.root {
-                None => Vec::new(),
-                Some(ref n) => vec![&**n],
-            },
-        }
+    /// Borrow the entry with the least key strictly greater than the given key.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.higher(&20), Some((&30, &"c")));
+    /// assert_eq!(t.higher(&30), None);
+    /// ```
+    pub fn higher<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        Node::first_after_prefix(&self.store, &self.root, |k| k.borrow() <= key)
+            .map(|n| (&n.key, &n.value))
     }
-}
 
-/// Return an mutable iterator over keys and values in the treap. The order is arbitrary.
-///
-/// ```
-/// let mut t = treap::TreapMap::new();
-/// t.extend(vec![(1, 200), (2, 120), (3, 330)].into_iter());
-///
-/// for (k, v) in &mut t {
-///     *v += *k;
-/// }
-/// assert_eq!(t.get(&2), Some(&122));
-/// ```
-impl<'a, K: Ord, V, Rng: rand::Rng> IntoIterator for &'a mut TreapMap<K, V, Rng> {
-    type Item = (&'a K, &'a mut V);
-    type IntoIter = IterMut<'a, K, V>;
+    /// Remove and return the entry with the smallest key in the treap.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(5, "a"), (2, "b"), (8, "c")]);
+    /// assert_eq!(t.pop_first(), Some((2, "b")));
+    /// assert_eq!(t.pop_first(), Some((5, "a")));
+    /// assert_eq!(t.len(), 1);
+    /// ```
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let path = Path::descend(&mut self.store, &mut self.root, |node| {
+            node.left.as_ref().map(|_| true)
+        });
+        let node = path.remove()?;
+        self.size -= 1;
+        Some((node.key, node.value))
+    }
 
-    fn into_iter(self) -> IterMut<'a, K, V> {
-        IterMut {
-            nodes: match self.root {
-                None => Vec::new(),
-                Some(ref mut n) => vec![&mut **n],
-            },
-        }
+    /// Remove and return the entry with the largest key in the treap.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(5, "a"), (2, "b"), (8, "c")]);
+    /// assert_eq!(t.pop_last(), Some((8, "c")));
+    /// assert_eq!(t.len(), 2);
+    /// ```
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let path = Path::descend(&mut self.store, &mut self.root, |node| {
+            node.right.as_ref().map(|_| false)
+        });
+        let node = path.remove()?;
+        self.size -= 1;
+        Some((node.key, node.value))
     }
-}
 
-impl<'a, K: Ord, V, Rng: rand::Rng> Index<&'a K> for TreapMap<K, V, Rng> {
-    type Output = V;
+    /// Returns the number of keys in the treap that are less than the given key. If the key is in
+    /// the treap this is its position in key order.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.rank(&20), 1);
+    /// assert_eq!(t.rank(&25), 2);
+    /// assert_eq!(t.rank(&5), 0);
+    /// ```
+    pub fn rank<Q: ?Sized + Ord>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        Node::rank(&self.store, &self.root, key)
+    }
 
-    fn index(&self, key: &K) -> &V {
-        self.get(key).expect("no entry found for key")
+    /// Returns the key and value at the given position in key order, counting from zero.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.select(0), Some((&10, &"a")));
+    /// assert_eq!(t.select(2), Some((&30, &"c")));
+    /// assert_eq!(t.select(3), None);
+    /// ```
+    pub fn select(&self, index: usize) -> Option<(&K, &V)> {
+        Node::select(&self.store, &self.root, index).map(|n| (&n.key, &n.value))
     }
-}
 
-impl<'a, K: Ord, V, Rng: rand::Rng> IndexMut<&'a K> for TreapMap<K, V, Rng> {
-    fn index_mut(&mut self, key: &K) -> &mut V {
-        self.get_mut(key).expect("no entry found for key")
+    /// Returns the key and value at the given position in key order, counting from zero. This is
+    /// another name for `select`; `iter_ordered().nth(index)` gives the same entry, also in
+    /// O(log n) expected time.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend(vec![(10, "a"), (20, "b"), (30, "c")]);
+    /// assert_eq!(t.nth_ordered(1), Some((&20, &"b")));
+    /// assert_eq!(t.nth_ordered(1), t.iter_ordered().nth(1));
+    /// ```
+    pub fn nth_ordered(&self, index: usize) -> Option<(&K, &V)> {
+        self.select(index)
     }
-}
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
-    type Item = (&'a K, &'a V);
+    #[cfg(test)]
+    pub(crate) fn check_invariants(&self) {
+        Node::check_invariants(&self.store, &self.root);
+        assert_eq!(self.size, Node::size(&self.store, &self.root));
+    }
 
-    fn next(&mut self) -> Option<(&'a K, &'a V)> {
-        match self.nodes.pop() {
-            None => None,
+    /// Returns an iterator over keys and values in the treap that gives the keys in sorted order.
+    /// The iterator can be reversed to give the keys in descending order. Skipping ahead with
+    /// `nth` takes O(log n) expected time.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.extend((1..10).map(|x| (x, "a")));
+    ///
+    /// let v: Vec<i32> = t.iter_ordered().map(|(&k, _)| k).collect();
+    /// assert_eq!(v, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    ///
+    /// let v: Vec<i32> = t.iter_ordered().rev().take(3).map(|(&k, _)| k).collect();
+    /// assert_eq!(v, vec![9, 8, 7]);
+    /// ```
+    pub fn iter_ordered(&self) -> OrderedIter<'_, K, V, S, A> {
+        OrderedIter::new(&self.store, &self.root)
+    }
+
+    /// Returns an iterator over the entries with keys in the given range, in key order. Finding
+    /// the start of the range takes O(log n) expected time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let v: Vec<_> = t.range(3..6).map(|(&k, &v)| (k, v)).collect();
+    /// assert_eq!(v, vec![(3, 30), (4, 40), (5, 50)]);
+    /// assert_eq!(t.range(8..).count(), 2);
+    /// ```
+    pub fn range<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> Range<'_, K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        Range::new(&self.store, &self.root, range)
+    }
+
+    /// Returns a mutable iterator over the entries with keys in the given range, in key order.
+    ///
+    /// With `Arena` storage the iterator borrows all the entries in the range when it is created,
+    /// which takes time and memory linear in their number.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (1..10).map(|k| (k, 0)).collect();
+    /// for (_, v) in t.range_mut(4..=5) {
+    ///     *v += 1;
+    /// }
+    /// assert_eq!(t.get(&3), Some(&0));
+    /// assert_eq!(t.get(&4), Some(&1));
+    /// assert_eq!(t.get(&5), Some(&1));
+    /// ```
+    pub fn range_mut<Q: ?Sized + Ord, R: RangeBounds<Q>>(
+        &mut self,
+        range: R,
+    ) -> RangeMut<'_, K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        check_range(&range);
+        let (start, end) = (range.start_bound(), range.end_bound());
+        let below = Node::count_prefix(&self.store, &self.root, |k| before_start(k, start));
+        let within = Node::count_prefix(&self.store, &self.root, |k| within_end(k, end));
+        let len = within.saturating_sub(below);
+        RangeMut {
+            iter: OrderedIterMut {
+                iter: self.store.ordered_mut(&mut self.root, below, len),
+            },
+        }
+    }
+
+    /// Returns a mutable iterator over keys and values in the treap that gives the keys in sorted
+    /// order.
+    ///
+    /// With `Arena` storage the iterator borrows every entry up front, in time and memory linear
+    /// in the size of the treap.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (1..5).map(|k| (k, 0)).collect();
+    /// let mut n = 0;
+    /// for (_, v) in t.iter_ordered_mut() {
+    ///     n += 1;
+    ///     *v = n;
+    /// }
+    /// assert_eq!(t.get(&1), Some(&1));
+    /// assert_eq!(t.get(&4), Some(&4));
+    /// ```
+    pub fn iter_ordered_mut(&mut self) -> OrderedIterMut<'_, K, V, S, A> {
+        OrderedIterMut {
+            iter: self.store.ordered_mut(&mut self.root, 0, self.size),
+        }
+    }
+
+    /// Returns an iterator that moves keys and values out of the treap in key order.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = vec![(3, "c"), (1, "a"), (2, "b")].into_iter().collect();
+    /// let v: Vec<_> = t.into_iter_ordered().collect();
+    /// assert_eq!(v, vec![(1, "a"), (2, "b"), (3, "c")]);
+    /// ```
+    pub fn into_iter_ordered(mut self) -> IntoOrderedIter<K, V, S, A> {
+        let mut store = mem::take(&mut self.store);
+        let nodes = Unlink::new(&mut store, self.root.take());
+        IntoOrderedIter { store, nodes }
+    }
+
+    /// Returns an iterator over the keys in the treap in sorted order.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = vec![(3, "c"), (1, "a"), (2, "b")].into_iter().collect();
+    /// let v: Vec<_> = t.keys().cloned().collect();
+    /// assert_eq!(v, vec![1, 2, 3]);
+    /// ```
+    pub fn keys(&self) -> Keys<'_, K, V, S, A> {
+        Keys {
+            iter: self.iter_ordered(),
+        }
+    }
+
+    /// Returns an iterator over the values in the treap in key order.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = vec![(3, "c"), (1, "a"), (2, "b")].into_iter().collect();
+    /// let v: Vec<_> = t.values().cloned().collect();
+    /// assert_eq!(v, vec!["a", "b", "c"]);
+    /// ```
+    pub fn values(&self) -> Values<'_, K, V, S, A> {
+        Values {
+            iter: self.iter_ordered(),
+        }
+    }
+
+    /// Returns a mutable iterator over the values in the treap in key order. Like
+    /// `iter_ordered_mut`, this takes linear time to create with `Arena` storage.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = vec![(1, 10), (2, 20)].into_iter().collect();
+    /// for v in t.values_mut() {
+    ///     *v += 1;
+    /// }
+    /// assert_eq!(t.values().cloned().collect::<Vec<_>>(), vec![11, 21]);
+    /// ```
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V, S, A> {
+        ValuesMut {
+            iter: self.iter_ordered_mut(),
+        }
+    }
+
+    /// Returns an iterator that moves the keys out of the treap in sorted order.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = vec![(3, "c"), (1, "a")].into_iter().collect();
+    /// assert_eq!(t.into_keys().collect::<Vec<_>>(), vec![1, 3]);
+    /// ```
+    pub fn into_keys(self) -> IntoKeys<K, V, S, A> {
+        IntoKeys {
+            iter: self.into_iter_ordered(),
+        }
+    }
+
+    /// Returns an iterator that moves the values out of the treap in key order.
+    ///
+    /// ```
+    /// let t: treap::TreapMap<_, _> = vec![(3, "c"), (1, "a")].into_iter().collect();
+    /// assert_eq!(t.into_values().collect::<Vec<_>>(), vec!["a", "c"]);
+    /// ```
+    pub fn into_values(self) -> IntoValues<K, V, S, A> {
+        IntoValues {
+            iter: self.into_iter_ordered(),
+        }
+    }
+}
+
+impl<K: Ord + Hash, V> TreapMap<K, V, HashedPriorities> {
+    /// Create an empty treap where the priority of each key is a hash of the key keyed with
+    /// `seed`. The shape of the treap then only depends on the set of keys it contains and not
+    /// on the order of the operations that produced it. See `HashedPriorities`.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::with_hashed_priorities(42);
+    /// t.insert("b", 2);
+    /// t.insert("a", 1);
+    /// assert_eq!(t.len(), 2);
+    /// ```
+    pub fn with_hashed_priorities(seed: u128) -> TreapMap<K, V, HashedPriorities> {
+        TreapMap::new_with_priorities(HashedPriorities::new(seed))
+    }
+}
+
+impl<K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    TreapMap<K, V, P, S, A>
+{
+    /// Move all elements from `other` into `self`, leaving `other` empty. If a key is present in
+    /// both treaps the value from `other` replaces the one in `self`.
+    ///
+    /// With `Arena` storage the nodes of `other` are first copied into the arena of `self`, which
+    /// takes time linear in the size of `other`.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = vec![(1, "a"), (2, "b")].into_iter().collect();
+    /// let mut u: treap::TreapMap<_, _> = vec![(2, "c"), (3, "d")].into_iter().collect();
+    /// t.append(&mut u);
+    /// assert_eq!(t.len(), 3);
+    /// assert_eq!(t.get(&2), Some(&"c"));
+    /// assert!(u.is_empty());
+    /// ```
+    pub fn append(&mut self, other: &mut TreapMap<K, V, P, S, A>) {
+        let theirs = self.store.adopt(&mut other.store, other.root.take());
+        self.root = Node::union(&mut self.store, self.root.take(), theirs);
+        self.size = Node::size(&self.store, &self.root);
+        other.size = 0;
+    }
+
+    /// Return a formatter that shows the shape of the treap, one node per line with the children
+    /// of a node indented below it. Left children are marked with `L` and right children with
+    /// `R`. Useful for debugging; the output depends on the priorities of the nodes.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.insert(1, "a");
+    /// t.insert(2, "b");
+    /// let shape = format!("{:?}", t.debug_tree());
+    /// assert!(shape == "1: \"a\"\n  R 2: \"b\"\n" || shape == "2: \"b\"\n  L 1: \"a\"\n");
+    /// ```
+    pub fn debug_tree(&self) -> DebugTree<'_, K, V, S, A> {
+        DebugTree {
+            store: &self.store,
+            root: Node::at(&self.store, &self.root),
+        }
+    }
+
+    /// Split the treap in two at the given key. Returns a treap with all the keys greater than or
+    /// equal to `key` and leaves the smaller keys in `self`. The new treap gets a clone of the
+    /// priority source of `self`; use `split_off_with` to give it a different one.
+    ///
+    /// With `Arena` storage the returned treap gets its own arena, and moving its nodes there takes
+    /// time linear in their number rather than O(log n).
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let u = t.split_off(&4);
+    /// assert_eq!(t.len(), 3);
+    /// assert_eq!(u.len(), 6);
+    /// assert_eq!(t.get(&3), Some(&30));
+    /// assert_eq!(u.get(&4), Some(&40));
+    /// ```
+    pub fn split_off<Q: ?Sized + Ord>(&mut self, key: &Q) -> TreapMap<K, V, P, S, A>
+    where
+        K: Borrow<Q>,
+        P: Clone,
+    {
+        let priorities = self.priorities.clone();
+        self.split_off_with(key, priorities)
+    }
+
+    /// Split the treap in two at the given key like `split_off`, using `priorities` as the priority
+    /// source of the new treap. Like `split_off`, this takes linear time with `Arena` storage.
+    ///
+    /// ```
+    /// use treap::priority::HashedPriorities;
+    ///
+    /// let mut t = treap::TreapMap::with_hashed_priorities(7);
+    /// t.extend((1..10).map(|k| (k, k * 10)));
+    /// let u = t.split_off_with(&4, HashedPriorities::new(8));
+    /// assert_eq!(t.len(), 3);
+    /// assert_eq!(u.len(), 6);
+    /// ```
+    pub fn split_off_with<Q: ?Sized + Ord>(
+        &mut self,
+        key: &Q,
+        priorities: P,
+    ) -> TreapMap<K, V, P, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        let (left, mid, right) = Node::split(&mut self.store, self.root.take(), key);
+        let right = Node::merge(&mut self.store, mid, right);
+        self.root = left;
+        self.size = Node::size(&self.store, &self.root);
+        let mut store = S::default();
+        let right = store.adopt(&mut self.store, right);
+        TreapMap {
+            size: Node::size(&store, &right),
+            root: right,
+            store,
+            priorities,
+        }
+    }
+
+    pub(crate) fn tree(&self) -> (&S, &Link<K, V, S, A>) {
+        (&self.store, &self.root)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn storage(&self) -> &S {
+        &self.store
+    }
+
+    // Replace the tree with the result of combining it with the tree of `other` using one of the
+    // set operations on nodes.
+    pub(crate) fn combine<F>(&mut self, mut other: TreapMap<K, V, P, S, A>, op: F)
+    where
+        F: FnOnce(&mut S, Link<K, V, S, A>, Link<K, V, S, A>) -> Link<K, V, S, A>,
+    {
+        let theirs = self.store.adopt(&mut other.store, other.root.take());
+        other.size = 0;
+        self.root = op(&mut self.store, self.root.take(), theirs);
+        self.size = Node::size(&self.store, &self.root);
+    }
+
+    /// Remove the entries with keys in the given range from the treap and return an iterator
+    /// over them in key order. The entries are removed even if the iterator is not consumed.
+    ///
+    /// With `Arena` storage the removed entries are moved into a separate arena for the iterator,
+    /// which takes time linear in their number.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let v: Vec<_> = t.drain_range(3..=5).collect();
+    /// assert_eq!(v, vec![(3, 30), (4, 40), (5, 50)]);
+    /// assert_eq!(t.len(), 6);
+    /// assert_eq!(t.get(&4), None);
+    /// ```
+    pub fn drain_range<Q: ?Sized + Ord, R: RangeBounds<Q>>(
+        &mut self,
+        range: R,
+    ) -> DrainRange<K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        check_range(&range);
+        let store = &mut self.store;
+        let (left, right) = Node::split_before(store, self.root.take(), range.start_bound());
+        let (mid, right) = Node::split_after(store, right, range.end_bound());
+        self.root = Node::merge(store, left, right);
+        self.size -= Node::size(store, &mid);
+        let mut drained = S::default();
+        let mid = drained.adopt(store, mid);
+        let nodes = Unlink::new(&mut drained, mid);
+        DrainRange {
+            iter: IntoOrderedIter {
+                store: drained,
+                nodes,
+            },
+        }
+    }
+
+    /// Retain only the entries for which `f` returns `true`. The treap is rebuilt in linear time.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (0..10).map(|k| (k, k)).collect();
+    /// t.retain(|k, v| {
+    ///     *v *= 10;
+    ///     k % 2 == 0
+    /// });
+    /// assert_eq!(t.len(), 5);
+    /// assert_eq!(t.get(&4), Some(&40));
+    /// assert_eq!(t.get(&5), None);
+    /// ```
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        self.extract_if(|k, v| !f(k, v)).for_each(drop);
+    }
+
+    /// Return an iterator that removes the entries for which `pred` returns `true` and yields
+    /// them in key order. If the iterator is dropped before it is exhausted the remaining entries
+    /// are kept. The treap is rebuilt in linear time when the iterator is dropped.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (0..10).map(|k| (k, k)).collect();
+    /// let odd: Vec<_> = t.extract_if(|k, _| k % 2 == 1).collect();
+    /// assert_eq!(odd, vec![(1, 1), (3, 3), (5, 5), (7, 7), (9, 9)]);
+    /// assert_eq!(t.len(), 5);
+    /// ```
+    pub fn extract_if<F: FnMut(&K, &mut V) -> bool>(
+        &mut self,
+        pred: F,
+    ) -> ExtractIf<'_, K, V, F, P, S, A> {
+        ExtractIf {
+            extraction: Extraction::new(self),
+            pred,
+        }
+    }
+
+    /// Join two treaps where every key in `left` is less than every key in `right`. The random
+    /// number generator of `left` is kept.
+    ///
+    /// With `Arena` storage the nodes of `right` are copied into the arena of `left`, so joining
+    /// takes time linear in the size of `right`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the largest key in `left` is not less than the smallest key in `right`.
+    ///
+    /// ```
+    /// let left: treap::TreapMap<_, _> = (0..5).map(|k| (k, k)).collect();
+    /// let right: treap::TreapMap<_, _> = (5..10).map(|k| (k, k)).collect();
+    /// let t = treap::TreapMap::join(left, right);
+    /// assert_eq!(t.len(), 10);
+    /// ```
+    pub fn join(
+        mut left: TreapMap<K, V, P, S, A>,
+        mut right: TreapMap<K, V, P, S, A>,
+    ) -> TreapMap<K, V, P, S, A> {
+        if let (Some((l, _)), Some((r, _))) = (left.last_key_value(), right.first_key_value()) {
+            assert!(
+                l < r,
+                "keys in left treap must be less than keys in right treap"
+            );
+        }
+        let theirs = left.store.adopt(&mut right.store, right.root.take());
+        left.root = Node::merge(&mut left.store, left.root.take(), theirs);
+        left.size += right.size;
+        right.size = 0;
+        left
+    }
+}
+
+impl<K: Ord, P: PrioritySource<K>, S: Storage<K, (), A>, A: Aggregate<K, ()>>
+    TreapMap<K, (), P, S, A>
+{
+    /// Remove the keys in the range `from..to` from the treap and push them onto `output` in key
+    /// order. Nothing is removed if `from` is not less than `to`. See `drain_range` for a version
+    /// that works with any value type.
+    pub fn delete_range(&mut self, from: K, to: K, output: &mut Vec<K>) {
+        if from >= to {
+            return;
+        }
+        output.extend(self.drain_range(from..to).map(|(k, _)| k));
+    }
+
+    // Put `key` in the place of an equal key and return the old key, or insert it if there is
+    // none. The tree keeps its shape when a key is replaced.
+    pub(crate) fn replace_key(&mut self, key: K) -> Option<K> {
+        Node::refresh(&mut self.store, &mut self.root);
+        let mut path = Path::search(&mut self.store, &mut self.root, &key);
+        if let Some(node) = path.end_mut() {
+            return Some(mem::replace(&mut node.key, key));
+        }
+        let priority = self.priorities.priority(&key);
+        path.insert(Node::new(key, (), priority));
+        self.size += 1;
+        None
+    }
+}
+
+impl<'a, K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    Entry<'a, K, V, P, S, A>
+{
+    /// Insert `default` if the entry is vacant and return a mutable reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Insert the result of `default` if the entry is vacant and return a mutable reference to the
+    /// value.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Modify the value in place if the entry is occupied.
+    ///
+    /// ```
+    /// let mut t = treap::TreapMap::new();
+    /// t.entry(1).and_modify(|v| *v += 1).or_insert(10);
+    /// t.entry(1).and_modify(|v| *v += 1).or_insert(10);
+    /// assert_eq!(t.get(&1), Some(&11));
+    /// ```
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Entry<'a, K, V, P, S, A> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+}
+
+impl<'a, K: Ord, V: Default, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    Entry<'a, K, V, P, S, A>
+{
+    /// Insert the default value if the entry is vacant and return a mutable reference to the
+    /// value.
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<&str, Vec<i32>> = treap::TreapMap::new();
+    /// t.entry("a").or_default().push(1);
+    /// t.entry("a").or_default().push(2);
+    /// assert_eq!(t.get(&"a"), Some(&vec![1, 2]));
+    /// ```
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    VacantEntry<'a, K, V, P, S, A>
+{
+    /// Returns the key that would be used when inserting a value.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert a value with the key of the entry and return a mutable reference to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let priority = self.priorities.priority(&self.key);
+        *self.size += 1;
+        let mut path = self.path;
+        path.insert(Node::new(self.key, value, priority));
+        &mut path
+            .into_end_mut()
+            .expect("inserted entry has a node")
+            .value
+    }
+}
+
+impl<'a, K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    OccupiedEntry<'a, K, V, P, S, A>
+{
+    /// Returns the key stored in the treap.
+    ///
+    /// ```
+    /// use treap::map::Entry;
+    ///
+    /// let mut t = treap::TreapMap::new();
+    /// t.insert(String::from("a"), 1);
+    /// let stored = t.get_key_value("a").unwrap().0.as_ptr();
+    /// if let Entry::Occupied(e) = t.entry(String::from("a")) {
+    ///     assert_eq!(e.key().as_ptr(), stored);
+    /// };
+    /// ```
+    pub fn key(&self) -> &K {
+        &self.node().key
+    }
+
+    /// Borrow the value of the entry.
+    pub fn get(&self) -> &V {
+        &self.node().value
+    }
+
+    /// Mutably borrow the value of the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self
+            .path
+            .end_mut()
+            .expect("occupied entry has a node")
+            .value
+    }
+
+    /// Convert the entry into a mutable reference to its value.
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self
+            .path
+            .into_end_mut()
+            .expect("occupied entry has a node")
+            .value
+    }
+
+    /// Replace the value of the entry and return the old value.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    /// Remove the entry from the treap and return its value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Remove the entry from the treap and return the stored key and value.
+    ///
+    /// ```
+    /// use treap::map::Entry;
+    ///
+    /// let mut t = treap::TreapMap::new();
+    /// t.insert(1, "a");
+    /// if let Entry::Occupied(e) = t.entry(1) {
+    ///     assert_eq!(e.remove_entry(), (1, "a"));
+    /// }
+    /// assert!(t.is_empty());
+    /// ```
+    pub fn remove_entry(self) -> (K, V) {
+        let node = self.path.remove().expect("occupied entry has a node");
+        *self.size -= 1;
+        (node.key, node.value)
+    }
+
+    fn node(&self) -> &Node<K, V, S, A> {
+        self.path.end().expect("occupied entry has a node")
+    }
+}
+
+// Returns true if `key` is below the lower bound `start` of a range.
+pub(crate) fn before_start<K: Borrow<Q>, Q: ?Sized + Ord>(key: &K, start: Bound<&Q>) -> bool {
+    match start {
+        Bound::Included(start) => key.borrow() < start,
+        Bound::Excluded(start) => key.borrow() <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+// Returns true if `key` is not above the upper bound `end` of a range.
+pub(crate) fn within_end<K: Borrow<Q>, Q: ?Sized + Ord>(key: &K, end: Bound<&Q>) -> bool {
+    match end {
+        Bound::Included(end) => key.borrow() <= end,
+        Bound::Excluded(end) => key.borrow() < end,
+        Bound::Unbounded => true,
+    }
+}
+
+pub(crate) fn check_range<Q: ?Sized + Ord, R: RangeBounds<Q>>(range: &R) {
+    use std::ops::Bound::{Excluded, Included};
+    match (range.start_bound(), range.end_bound()) {
+        (Excluded(start), Excluded(end)) if start == end => {
+            panic!("range start and end are equal and excluded")
+        }
+        (Included(start), Included(end))
+        | (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end))
+            if start > end =>
+        {
+            panic!("range start is greater than range end")
+        }
+        _ => {}
+    }
+}
+
+impl<K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> Extend<(K, V)>
+    for TreapMap<K, V, P, S, A>
+{
+    #[inline]
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FromIterator<(K, V)>
+    for TreapMap<K, V, XorShiftRng, S, A>
+{
+    #[inline]
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> TreapMap<K, V, XorShiftRng, S, A> {
+        let mut treap = TreapMap::default();
+        treap.extend(iter);
+        treap
+    }
+}
+
+impl<K: Ord, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Default
+    for TreapMap<K, V, XorShiftRng, S, A>
+{
+    fn default() -> TreapMap<K, V, XorShiftRng, S, A> {
+        TreapMap::new_in(S::default())
+    }
+}
+
+impl<K: Clone, V: Clone, P: Clone, S: Storage<K, V, A>, A: Aggregate<K, V>> Clone
+    for TreapMap<K, V, P, S, A>
+{
+    fn clone(&self) -> TreapMap<K, V, P, S, A> {
+        let (store, root) = self.store.clone_tree(&self.root);
+        TreapMap {
+            root,
+            store,
+            size: self.size,
+            priorities: self.priorities.clone(),
+        }
+    }
+}
+
+impl<K, V, P, S: Storage<K, V, A>, A: Aggregate<K, V>> Drop for TreapMap<K, V, P, S, A> {
+    fn drop(&mut self) {
+        self.store.drop_tree(self.root.take());
+    }
+}
+
+/// Treaps are compared by their entries in key order. The shape of the trees and the state of
+/// their priority sources are ignored.
+impl<K: Ord, V: PartialEq, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> PartialEq
+    for TreapMap<K, V, P, S, A>
+{
+    fn eq(&self, other: &TreapMap<K, V, P, S, A>) -> bool {
+        self.len() == other.len() && self.iter_ordered().eq(other.iter_ordered())
+    }
+}
+
+impl<K: Ord, V: Eq, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> Eq
+    for TreapMap<K, V, P, S, A>
+{
+}
+
+impl<K: Ord, V: PartialOrd, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>>
+    PartialOrd for TreapMap<K, V, P, S, A>
+{
+    fn partial_cmp(&self, other: &TreapMap<K, V, P, S, A>) -> Option<Ordering> {
+        self.iter_ordered().partial_cmp(other.iter_ordered())
+    }
+}
+
+impl<K: Ord, V: Ord, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> Ord
+    for TreapMap<K, V, P, S, A>
+{
+    fn cmp(&self, other: &TreapMap<K, V, P, S, A>) -> Ordering {
+        self.iter_ordered().cmp(other.iter_ordered())
+    }
+}
+
+impl<
+        K: Ord + fmt::Debug,
+        V: fmt::Debug,
+        P: PrioritySource<K>,
+        S: Storage<K, V, A>,
+        A: Aggregate<K, V>,
+    > fmt::Debug for TreapMap<K, V, P, S, A>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter_ordered()).finish()
+    }
+}
+
+impl<'a, K: fmt::Debug, V: fmt::Debug, S: Storage<K, V, A>, A: Aggregate<K, V>> fmt::Debug
+    for DebugTree<'a, K, V, S, A>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let root = match self.root {
+            Some(root) => root,
+            None => return writeln!(f, "(empty)"),
+        };
+        // Pre-order walk; the right child is pushed first so the left one is printed first
+        let mut stack = vec![(root, 0, "")];
+        while let Some((node, depth, side)) = stack.pop() {
+            for _ in 0..depth {
+                f.write_str("  ")?;
+            }
+            writeln!(f, "{}{:?}: {:?}", side, node.key, node.value)?;
+            if let Some(right) = Node::at(self.store, &node.right) {
+                stack.push((right, depth + 1, "R "));
+            }
+            if let Some(left) = Node::at(self.store, &node.left) {
+                stack.push((left, depth + 1, "L "));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K: Ord + Hash, V: Hash, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> Hash
+    for TreapMap<K, V, P, S, A>
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for entry in self.iter_ordered() {
+            entry.hash(state);
+        }
+    }
+}
+
+/// Return an iterator that moves keys and values out of treap. The order is arbitrary.
+///
+/// ```
+/// let mut t = treap::TreapMap::new();
+/// t.extend(vec![(1, "red"), (2, "blue"), (3, "green")].into_iter());
+///
+/// // Print keys and values in arbitrary order.
+/// for (k, v) in t {
+///     println!("{}: {}", k, v);
+/// }
+/// ```
+impl<K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> IntoIterator
+    for TreapMap<K, V, P, S, A>
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, S, A>;
+
+    fn into_iter(mut self) -> IntoIter<K, V, S, A> {
+        IntoIter {
+            nodes: self.root.take().into_iter().collect(),
+            store: mem::take(&mut self.store),
+            remaining: self.size,
+        }
+    }
+}
+
+/// Return an iterator over keys and values in the treap. The order is arbitrary.
+///
+/// ```
+/// let mut t = treap::TreapMap::new();
+/// t.extend(vec![(1, 200), (2, 120), (3, 330)].into_iter());
+///
+/// let sum = (&t).into_iter().fold(0, |s, (&k, &v)| s + k + v);
+/// assert_eq!(sum, 656);
+/// ```
+impl<'a, K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> IntoIterator
+    for &'a TreapMap<K, V, P, S, A>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, S, A>;
+
+    fn into_iter(self) -> Iter<'a, K, V, S, A> {
+        Iter {
+            store: &self.store,
+            nodes: Node::at(&self.store, &self.root).into_iter().collect(),
+            remaining: self.size,
+        }
+    }
+}
+
+/// Return an mutable iterator over keys and values in the treap. The order is arbitrary.
+///
+/// ```
+/// let mut t = treap::TreapMap::new();
+/// t.extend(vec![(1, 200), (2, 120), (3, 330)].into_iter());
+///
+/// for (k, v) in &mut t {
+///     *v += *k;
+/// }
+/// assert_eq!(t.get(&2), Some(&122));
+/// ```
+impl<'a, K: Ord, V, P: PrioritySource<K>, S: Storage<K, V, A>, A: Aggregate<K, V>> IntoIterator
+    for &'a mut TreapMap<K, V, P, S, A>
+{
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V, S, A>;
+
+    fn into_iter(self) -> IterMut<'a, K, V, S, A> {
+        IterMut {
+            iter: self.store.iter_mut(&mut self.root),
+        }
+    }
+}
+
+impl<
+        K: Ord + Borrow<Q>,
+        Q: ?Sized + Ord,
+        V,
+        P: PrioritySource<K>,
+        S: Storage<K, V, A>,
+        A: Aggregate<K, V>,
+    > Index<&Q> for TreapMap<K, V, P, S, A>
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<
+        K: Ord + Borrow<Q>,
+        Q: ?Sized + Ord,
+        V,
+        P: PrioritySource<K>,
+        S: Storage<K, V, A>,
+        A: Aggregate<K, V>,
+    > IndexMut<&Q> for TreapMap<K, V, P, S, A>
+{
+    fn index_mut(&mut self, key: &Q) -> &mut V {
+        self.get_mut(key).expect("no entry found for key")
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for Iter<'a, K, V, S, A> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        match self.nodes.pop() {
+            None => None,
             Some(node) => {
-                if let Some(ref boxed) = node.left {
-                    self.nodes.push(&**boxed);
-                }
-                if let Some(ref boxed) = node.right {
-                    self.nodes.push(&**boxed);
-                }
+                self.nodes.extend(Node::at(self.store, &node.left));
+                self.nodes.extend(Node::at(self.store, &node.right));
+                self.remaining -= 1;
                 Some((&node.key, &node.value))
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for Iter<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for Iter<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for IterMut<'a, K, V, S, A> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for IterMut<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for IterMut<'a, K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for IntoIter<K, V, S, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.nodes.pop() {
+            None => None,
+            Some(node) => {
+                let node = self.store.take(node);
+                self.nodes.extend(node.left);
+                self.nodes.extend(node.right);
+                self.remaining -= 1;
+                Some((node.key, node.value))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for IntoIter<K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Drop for IntoIter<K, V, S, A> {
+    fn drop(&mut self) {
+        for node in self.nodes.drain(..) {
+            self.store.drop_tree(Some(node));
+        }
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for IntoIter<K, V, S, A> {}
+
+impl<'a, K: Ord, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Range<'a, K, V, S, A> {
+    pub(crate) fn new<Q, R>(
+        store: &'a S,
+        root: &'a Link<K, V, S, A>,
+        range: R,
+    ) -> Range<'a, K, V, S, A>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        R: RangeBounds<Q>,
+    {
+        check_range(&range);
+        let (start, end) = (range.start_bound(), range.end_bound());
+        let below = Node::count_prefix(store, root, |k| before_start(k, start));
+        let within = Node::count_prefix(store, root, |k| within_end(k, end));
+        let mut iter = OrderedIter {
+            store,
+            front: Vec::new(),
+            back: Vec::new(),
+            remaining: within.saturating_sub(below),
+        };
+        let mut cur = Node::at(store, root);
+        while let Some(node) = cur {
+            if before_start(&node.key, start) {
+                cur = Node::at(store, &node.right);
+            } else {
+                iter.front.push(node);
+                cur = Node::at(store, &node.left);
+            }
+        }
+        let mut cur = Node::at(store, root);
+        while let Some(node) = cur {
+            if within_end(&node.key, end) {
+                iter.back.push(node);
+                cur = Node::at(store, &node.right);
+            } else {
+                cur = Node::at(store, &node.left);
+            }
+        }
+        Range { iter }
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for Range<'a, K, V, S, A> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<(&'a K, &'a V)> {
+        self.iter.nth(n)
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> DoubleEndedIterator
+    for Range<'a, K, V, S, A>
+{
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        self.iter.next_back()
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for Range<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for Range<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for RangeMut<'a, K, V, S, A> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for RangeMut<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for RangeMut<'a, K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for DrainRange<K, V, S, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for DrainRange<K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for DrainRange<K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator
+    for OrderedIterMut<'a, K, V, S, A>
+{
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for OrderedIterMut<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator
+    for OrderedIterMut<'a, K, V, S, A>
+{
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for IntoOrderedIter<K, V, S, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        let node = self.nodes.next_node(&mut self.store)?;
+        let node = self.store.take(node);
+        Some((node.key, node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.nodes.remaining, Some(self.nodes.remaining))
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for IntoOrderedIter<K, V, S, A>
+{
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Drop for IntoOrderedIter<K, V, S, A> {
+    fn drop(&mut self) {
+        self.nodes.drop_rest(&mut self.store);
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for IntoOrderedIter<K, V, S, A> {}
+
+impl<'a, K: Ord, V, P, S: Storage<K, V, A>, A: Aggregate<K, V>> Extraction<'a, K, V, P, S, A> {
+    pub(crate) fn new(map: &'a mut TreapMap<K, V, P, S, A>) -> Extraction<'a, K, V, P, S, A> {
+        let root = map.root.take();
+        let nodes = Unlink::new(&mut map.store, root);
+        map.size = 0;
+        Extraction {
+            map,
+            nodes,
+            kept: Vec::new(),
+        }
+    }
+
+    pub(crate) fn next_matching<F>(&mut self, pred: &mut F) -> Option<(K, V)>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let store = &mut self.map.store;
+        while let Some(mut node) = self.nodes.next_node(store) {
+            let n = store.node_mut(&mut node);
+            if pred(&n.key, &mut n.value) {
+                let node = store.take(node);
+                return Some((node.key, node.value));
+            }
+            self.kept.push(node);
+        }
+        None
+    }
+
+    pub(crate) fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.nodes.remaining))
+    }
+}
+
+impl<'a, K: Ord, V, P, S: Storage<K, V, A>, A: Aggregate<K, V>> Drop
+    for Extraction<'a, K, V, P, S, A>
+{
+    fn drop(&mut self) {
+        let store = &mut self.map.store;
+        while let Some(node) = self.nodes.next_node(store) {
+            self.kept.push(node);
+        }
+        self.map.size = self.kept.len();
+        self.map.root = Node::from_sorted_nodes(store, self.kept.drain(..))
+            .expect("kept nodes are in key order");
+    }
+}
+
+impl<'a, K: Ord, V, F, P, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator
+    for ExtractIf<'a, K, V, F, P, S, A>
+where
+    F: FnMut(&K, &mut V) -> bool,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.extraction.next_matching(&mut self.pred)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.extraction.size_hint()
+    }
+}
+
+impl<'a, K: Ord, V, F, P, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator
+    for ExtractIf<'a, K, V, F, P, S, A>
+where
+    F: FnMut(&K, &mut V) -> bool,
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for Keys<'a, K, V, S, A> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
-impl<'a, K, V> Iterator for IterMut<'a, K, V> {
-    type Item = (&'a K, &'a mut V);
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> DoubleEndedIterator
+    for Keys<'a, K, V, S, A>
+{
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.iter.next_back().map(|(k, _)| k)
+    }
+}
 
-    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
-        match self.nodes.pop() {
-            None => None,
-            Some(node) => {
-                if let Some(boxed) = node.left.as_mut() {
-                    self.nodes.push(&mut **boxed);
-                }
-                if let Some(boxed) = node.right.as_mut() {
-                    self.nodes.push(&mut **boxed);
-                }
-                Some((&node.key, &mut node.value))
-            }
-        }
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for Keys<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for Keys<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for Values<'a, K, V, S, A> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
-impl<K, V> Iterator for IntoIter<K, V> {
-    type Item = (K, V);
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> DoubleEndedIterator
+    for Values<'a, K, V, S, A>
+{
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.iter.next_back().map(|(_, v)| v)
+    }
+}
 
-    fn next(&mut self) -> Option<(K, V)> {
-        match self.nodes.pop() {
-            None => None,
-            Some(node) => {
-                if let Some(boxed) = node.left {
-                    self.nodes.push(*boxed);
-                }
-                if let Some(boxed) = node.right {
-                    self.nodes.push(*boxed);
-                }
-                Some((node.key, node.value))
-            }
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for Values<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for Values<'a, K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for ValuesMut<'a, K, V, S, A> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for ValuesMut<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator
+    for ValuesMut<'a, K, V, S, A>
+{
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for IntoKeys<K, V, S, A> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for IntoKeys<K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for IntoKeys<K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for IntoValues<K, V, S, A> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator for IntoValues<K, V, S, A> {}
+
+impl<K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator for IntoValues<K, V, S, A> {}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> OrderedIter<'a, K, V, S, A> {
+    pub(crate) fn new(store: &'a S, root: &'a Link<K, V, S, A>) -> OrderedIter<'a, K, V, S, A> {
+        let mut iter = OrderedIter {
+            store,
+            front: Vec::new(),
+            back: Vec::new(),
+            remaining: Node::size(store, root),
+        };
+        iter.push_left_spine(Node::at(store, root));
+        iter.push_right_spine(Node::at(store, root));
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut subtree: Option<&'a Node<K, V, S, A>>) {
+        while let Some(node) = subtree {
+            self.front.push(node);
+            subtree = Node::at(self.store, &node.left);
+        }
+    }
+
+    fn push_right_spine(&mut self, mut subtree: Option<&'a Node<K, V, S, A>>) {
+        while let Some(node) = subtree {
+            self.back.push(node);
+            subtree = Node::at(self.store, &node.right);
         }
     }
 }
 
-impl<'a, K, V> Iterator for OrderedIter<'a, K, V> {
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> Iterator for OrderedIter<'a, K, V, S, A> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<(&'a K, &'a V)> {
-        use self::Traversal::{Left, Right};
-        loop {
-            match self.nodes.pop() {
-                None => return None,
-                Some(Left(node)) => {
-                    self.nodes.push(Right(node));
-                    if let Some(ref node_box) = node.left {
-                        self.nodes.push(Left(&**node_box));
-                    }
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.front.pop()?;
+        self.push_left_spine(Node::at(self.store, &node.right));
+        Some((&node.key, &node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+    fn nth(&mut self, mut n: usize) -> Option<(&'a K, &'a V)> {
+        if n >= self.remaining {
+            self.remaining = 0;
+            return None;
+        }
+        self.remaining -= n;
+        while n > 0 {
+            let node = self.front.pop()?;
+            n -= 1;
+            // Skip whole subtrees using their sizes
+            let mut subtree = Node::at(self.store, &node.right);
+            while let Some(node) = subtree {
+                if n >= node.size {
+                    n -= node.size;
+                    break;
                 }
-                Some(Right(node)) => {
-                    if let Some(ref node_box) = node.right {
-                        self.nodes.push(Left(&**node_box));
-                    }
-                    return Some((&node.key, &node.value));
+                self.front.push(node);
+                let left = Node::size(self.store, &node.left);
+                if n >= left {
+                    n -= left;
+                    break;
                 }
+                subtree = Node::at(self.store, &node.left);
             }
         }
+        self.next()
     }
 }
 
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> DoubleEndedIterator
+    for OrderedIter<'a, K, V, S, A>
+{
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.back.pop()?;
+        self.push_right_spine(Node::at(self.store, &node.left));
+        Some((&node.key, &node.value))
+    }
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for OrderedIter<'a, K, V, S, A>
+{
+}
+
+impl<'a, K, V, S: Storage<K, V, A>, A: Aggregate<K, V>> FusedIterator
+    for OrderedIter<'a, K, V, S, A>
+{
+}
+
 #[cfg(test)]
 mod tests {
     use super::TreapMap;
+    use node::Node;
+    use priority::{HashedPriorities, PrioritySource};
     use std::iter::FromIterator;
+    use std::ops::Bound;
+    use storage::Boxed;
 
     #[test]
     fn test_len() {
@@ -482,10 +2283,11 @@ mod tests {
 
     #[test]
     fn delete_range() {
-        let mut t = TreapMap::from_iter((1..6).map(|k| (k, ())));
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((1..6).map(|k| (k, ())));
 
         let mut out = Vec::new();
         t.delete_range(2, 4, &mut out);
+        t.check_invariants();
         assert_eq!(t.len(), 3);
         assert_eq!(&out[..], &[2, 3]);
 
@@ -493,13 +2295,621 @@ mod tests {
         assert_eq!(&remaining[..], &[1, 4, 5]);
     }
 
+    #[test]
+    fn delete_range_reversed() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((1..6).map(|k| (k, ())));
+
+        let mut out = Vec::new();
+        t.delete_range(4, 2, &mut out);
+        t.delete_range(3, 3, &mut out);
+        assert_eq!(t.len(), 5);
+        assert_eq!(out.len(), 0);
+    }
+
     #[test]
     fn delete_range_nonexisting() {
-        let mut t = TreapMap::from_iter((1..6).map(|k| (k, ())));
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((1..6).map(|k| (k, ())));
 
         let mut out = Vec::new();
         t.delete_range(10, 12, &mut out);
         assert_eq!(t.len(), 5);
         assert_eq!(out.len(), 0);
     }
+
+    #[test]
+    fn split_off() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k, k)));
+
+        let u = t.split_off(&40);
+        t.check_invariants();
+        u.check_invariants();
+        assert_eq!(t.len(), 40);
+        assert_eq!(u.len(), 60);
+        let left: Vec<_> = t.iter_ordered().map(|(k, _)| *k).collect();
+        let right: Vec<_> = u.iter_ordered().map(|(k, _)| *k).collect();
+        assert_eq!(left, (0..40).collect::<Vec<_>>());
+        assert_eq!(right, (40..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_off_bounds() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..10).map(|k| (k, k)));
+
+        let u = t.split_off(&100);
+        assert_eq!((t.len(), u.len()), (10, 0));
+        let u = t.split_off(&-1);
+        assert_eq!((t.len(), u.len()), (0, 10));
+    }
+
+    #[test]
+    fn append() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..50).map(|k| (k, 0)));
+        let mut u: TreapMap<_, _> = TreapMap::from_iter((25..75).map(|k| (k, 1)));
+
+        t.append(&mut u);
+        t.check_invariants();
+        assert_eq!(t.len(), 75);
+        assert!(u.is_empty());
+        let v: Vec<_> = t.iter_ordered().map(|(&k, &v)| (k, v)).collect();
+        let expected: Vec<_> = (0..75).map(|k| (k, if k < 25 { 0 } else { 1 })).collect();
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn join() {
+        let left: TreapMap<_, _> = TreapMap::from_iter((0..30).map(|k| (k, k)));
+        let right: TreapMap<_, _> = TreapMap::from_iter((30..60).map(|k| (k, k)));
+
+        let mut t = TreapMap::join(left, right);
+        t.check_invariants();
+        assert_eq!(t.len(), 60);
+        for k in 0..60 {
+            assert_eq!(t.remove(&k), Some(k));
+            t.check_invariants();
+        }
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn join_overlapping() {
+        let left: TreapMap<_, _> = TreapMap::from_iter((0..30).map(|k| (k, k)));
+        let right: TreapMap<_, _> = TreapMap::from_iter((20..60).map(|k| (k, k)));
+        TreapMap::join(left, right);
+    }
+
+    #[test]
+    fn rank_select() {
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k * 2, k)));
+
+        for i in 0..100 {
+            assert_eq!(t.select(i), Some((&(i as i32 * 2), &(i as i32))));
+            assert_eq!(t.rank(&(i as i32 * 2)), i);
+            assert_eq!(t.rank(&(i as i32 * 2 + 1)), i + 1);
+        }
+        assert_eq!(t.select(100), None);
+        assert_eq!(t.rank(&-1), 0);
+    }
+
+    #[test]
+    fn ordered_nth() {
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k, ())));
+
+        for i in 0..100 {
+            assert_eq!(t.iter_ordered().nth(i), Some((&(i as i32), &())));
+        }
+        let mut iter = t.iter_ordered();
+        assert_eq!(iter.nth(10), Some((&10, &())));
+        assert_eq!(iter.nth(20), Some((&31, &())));
+        assert_eq!(iter.next(), Some((&32, &())));
+        assert_eq!(iter.nth(100), None);
+    }
+
+    #[test]
+    fn drain_range() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..20).map(|k| (k, k * 10)));
+
+        let v: Vec<_> = t.drain_range(5..10).collect();
+        t.check_invariants();
+        assert_eq!(v, (5..10).map(|k| (k, k * 10)).collect::<Vec<_>>());
+        assert_eq!(t.len(), 15);
+
+        let v: Vec<_> = t.drain_range(15..).map(|(k, _)| k).collect();
+        assert_eq!(v, (15..20).collect::<Vec<_>>());
+        let v: Vec<_> = t.drain_range(..=2).map(|(k, _)| k).collect();
+        assert_eq!(v, vec![0, 1, 2]);
+        t.check_invariants();
+
+        let remaining: Vec<_> = t.iter_ordered().map(|(k, _)| *k).collect();
+        assert_eq!(remaining, vec![3, 4, 10, 11, 12, 13, 14]);
+        assert_eq!(t.len(), 7);
+    }
+
+    #[test]
+    fn drain_range_excluded_start() {
+        use std::ops::Bound::{Excluded, Included};
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..10).map(|k| (k, ())));
+
+        let v: Vec<_> = t
+            .drain_range((Excluded(3), Included(6)))
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(v, vec![4, 5, 6]);
+        assert_eq!(t.drain_range(..).count(), 7);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn drain_range_inverted() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..10).map(|k| (k, ())));
+        let (start, end) = (6, 3);
+        t.drain_range(start..end);
+    }
+
+    #[test]
+    fn range() {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..50).map(|k| (k * 2, k)));
+
+        let keys = |r: (Bound<i32>, Bound<i32>)| t.range(r).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys((Included(10), Excluded(16))), vec![10, 12, 14]);
+        assert_eq!(keys((Excluded(10), Included(16))), vec![12, 14, 16]);
+        assert_eq!(keys((Included(11), Included(15))), vec![12, 14]);
+        assert_eq!(keys((Unbounded, Excluded(4))), vec![0, 2]);
+        assert_eq!(keys((Excluded(94), Unbounded)), vec![96, 98]);
+        assert_eq!(keys((Included(200), Unbounded)), Vec::<i32>::new());
+        assert_eq!(keys((Excluded(10), Excluded(11))), Vec::<i32>::new());
+        assert_eq!(t.range(..).count(), 50);
+        assert_eq!(t.range(10..20).size_hint(), (5, Some(5)));
+    }
+
+    #[test]
+    fn range_mut() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..50).map(|k| (k, 0)));
+
+        for (k, v) in t.range_mut(10..=20) {
+            *v = *k;
+        }
+        let v: Vec<_> = t.iter_ordered().map(|(_, v)| *v).collect();
+        let expected: Vec<_> = (0..50)
+            .map(|k| if (10..=20).contains(&k) { k } else { 0 })
+            .collect();
+        assert_eq!(v, expected);
+        assert_eq!(t.range_mut(45..).count(), 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn range_excluded_equal() {
+        use std::ops::Bound::Excluded;
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..10).map(|k| (k, ())));
+        t.range((Excluded(3), Excluded(3)));
+    }
+
+    #[test]
+    fn entry() {
+        use super::Entry;
+        let mut t = TreapMap::new();
+
+        for i in 0..200 {
+            *t.entry(i % 50).or_insert(0) += 1;
+            t.check_invariants();
+        }
+        assert_eq!(t.len(), 50);
+        assert!(t.iter_ordered().all(|(_, &v)| v == 4));
+
+        match t.entry(10) {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.insert(7), 4);
+                assert_eq!(e.remove(), 7);
+            }
+            Entry::Vacant(_) => panic!("expected occupied entry"),
+        }
+        t.check_invariants();
+        assert_eq!(t.len(), 49);
+        match t.entry(10) {
+            Entry::Vacant(e) => assert_eq!(*e.insert(3), 3),
+            Entry::Occupied(_) => panic!("expected vacant entry"),
+        }
+        assert_eq!(t.get(&10), Some(&3));
+        t.check_invariants();
+    }
+
+    #[test]
+    fn entry_matches_btreemap() {
+        use super::Entry;
+        use rand::{Rng, SeedableRng, XorShiftRng};
+        use std::collections::BTreeMap;
+
+        let mut rng = XorShiftRng::from_seed([5; 16]);
+        let mut t = TreapMap::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..5000 {
+            let k = rng.gen_range(0, 300);
+            match (t.entry(k), rng.gen_range(0, 3)) {
+                (Entry::Occupied(e), 0) => assert_eq!(Some(e.remove()), expected.remove(&k)),
+                (Entry::Occupied(mut e), 1) => {
+                    *e.get_mut() += 1;
+                    *expected.get_mut(&k).unwrap() += 1;
+                }
+                (Entry::Vacant(e), 0) => {
+                    e.insert(i);
+                    expected.insert(k, i);
+                }
+                // Leave the entry alone; the tree must be put back as it was
+                (e, _) => assert_eq!(e.key(), &k),
+            }
+            assert_eq!(t.len(), expected.len());
+            t.check_invariants();
+        }
+        assert!(t.iter_ordered().eq(expected.iter()));
+    }
+
+    #[test]
+    fn borrowed_lookups() {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..20).map(|k| (format!("{:02}", k), k)));
+
+        assert_eq!(t.get("05"), Some(&5));
+        assert!(t.contains_key("19"));
+        assert!(!t.contains_key("20"));
+        *t.get_mut("07").unwrap() += 100;
+        assert_eq!(t["07"], 107);
+        assert_eq!(t.rank("10"), 10);
+        let v: Vec<_> = t
+            .range::<str, _>((Included("03"), Excluded("06")))
+            .map(|(_, v)| *v)
+            .collect();
+        assert_eq!(v, vec![3, 4, 5]);
+        assert_eq!(t.remove("07"), Some(107));
+        assert_eq!(
+            t.drain_range::<str, _>((Included("15"), Unbounded)).count(),
+            5
+        );
+        let u = t.split_off("10");
+        assert_eq!((t.len(), u.len()), (9, 5));
+    }
+
+    #[test]
+    fn pop_first_last() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k, k)));
+
+        for i in 0..50 {
+            assert_eq!(t.first_key_value(), Some((&i, &i)));
+            assert_eq!(t.pop_first(), Some((i, i)));
+            t.check_invariants();
+            assert_eq!(t.last_key_value(), Some((&(99 - i), &(99 - i))));
+            assert_eq!(t.pop_last(), Some((99 - i, 99 - i)));
+            t.check_invariants();
+        }
+        assert!(t.is_empty());
+        assert_eq!(t.pop_first(), None);
+        assert_eq!(t.pop_last(), None);
+        assert_eq!(t.first_key_value(), None);
+    }
+
+    #[test]
+    fn nearest_keys() {
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..50).map(|k| (k * 3, k)));
+
+        let keys: Vec<_> = t.iter_ordered().map(|(&k, _)| k).collect();
+        for k in -2..155 {
+            let floor = keys.iter().rev().find(|&&x| x <= k);
+            let lower = keys.iter().rev().find(|&&x| x < k);
+            let ceiling = keys.iter().find(|&&x| x >= k);
+            let higher = keys.iter().find(|&&x| x > k);
+            assert_eq!(t.floor(&k).map(|(k, _)| k), floor);
+            assert_eq!(t.lower(&k).map(|(k, _)| k), lower);
+            assert_eq!(t.ceiling(&k).map(|(k, _)| k), ceiling);
+            assert_eq!(t.higher(&k).map(|(k, _)| k), higher);
+        }
+    }
+
+    #[test]
+    fn ordered_iter_double_ended() {
+        let t: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k, ())));
+
+        let v: Vec<_> = t.iter_ordered().rev().map(|(&k, _)| k).collect();
+        assert_eq!(v, (0..100).rev().collect::<Vec<_>>());
+
+        let mut iter = t.iter_ordered();
+        assert_eq!(iter.len(), 100);
+        for i in 0..50 {
+            assert_eq!(iter.next(), Some((&i, &())));
+            assert_eq!(iter.next_back(), Some((&(99 - i), &())));
+            assert_eq!(iter.len(), 98 - 2 * i as usize);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+
+        let mut iter = t.iter_ordered();
+        assert_eq!(iter.next_back(), Some((&99, &())));
+        assert_eq!(iter.nth(97), Some((&97, &())));
+        assert_eq!(iter.next(), Some((&98, &())));
+        assert_eq!(iter.next(), None);
+
+        let v: Vec<_> = t.range(10..20).rev().map(|(&k, _)| k).collect();
+        assert_eq!(v, (10..20).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_size_hint() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..10).map(|k| (k, k)));
+
+        let mut iter = (&t).into_iter();
+        assert_eq!(iter.len(), 10);
+        iter.next();
+        assert_eq!(iter.size_hint(), (9, Some(9)));
+        assert_eq!((&mut t).into_iter().len(), 10);
+        assert_eq!(t.drain_range(2..5).len(), 3);
+        let mut iter = t.into_iter();
+        iter.next();
+        assert_eq!(iter.len(), 6);
+    }
+
+    #[test]
+    fn ordered_mut_and_owning() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..100).rev().map(|k| (k, 0)));
+
+        let mut n = 0;
+        for (k, v) in t.iter_ordered_mut() {
+            assert_eq!(*k, n);
+            *v = n * 2;
+            n += 1;
+        }
+        assert_eq!(n, 100);
+        for v in t.values_mut() {
+            *v += 1;
+        }
+        let keys: Vec<_> = t.keys().cloned().collect();
+        let values: Vec<_> = t.values().cloned().collect();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        assert_eq!(values, (0..100).map(|k| k * 2 + 1).collect::<Vec<_>>());
+
+        let v: Vec<_> = t.clone().into_iter_ordered().collect();
+        assert_eq!(v, (0..100).map(|k| (k, k * 2 + 1)).collect::<Vec<_>>());
+        assert_eq!(t.clone().into_keys().collect::<Vec<_>>(), keys);
+        assert_eq!(t.into_values().collect::<Vec<_>>(), values);
+    }
+
+    #[test]
+    fn retain_and_extract_if() {
+        let mut t: TreapMap<_, _> = TreapMap::from_iter((0..1000).map(|k| (k, k)));
+        t.retain(|k, v| {
+            *v += 1;
+            k % 3 != 0
+        });
+        t.check_invariants();
+        assert_eq!(t.len(), 666);
+        assert_eq!(t.get(&3), None);
+        assert_eq!(t.get(&4), Some(&5));
+
+        let removed: Vec<_> = t.extract_if(|k, _| k % 2 == 0).map(|(k, _)| k).collect();
+        t.check_invariants();
+        assert_eq!(removed.len(), 333);
+        assert!(removed.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(t.len(), 333);
+        assert!(t.keys().all(|k| k % 2 == 1 && k % 3 != 0));
+
+        // Dropping the iterator early keeps the rest
+        let first: Vec<_> = t.extract_if(|_, _| true).take(10).collect();
+        t.check_invariants();
+        assert_eq!(first.len(), 10);
+        assert_eq!(t.len(), 323);
+        assert_eq!(t.first_key_value().map(|(k, _)| *k), Some(31));
+    }
+
+    #[test]
+    fn from_sorted() {
+        let t = TreapMap::from_sorted_iter((0..1000).map(|k| (k, k + 1)));
+        t.check_invariants();
+        assert_eq!(t.len(), 1000);
+        assert!(t
+            .iter_ordered()
+            .map(|(k, v)| (*k, *v))
+            .eq((0..1000).map(|k| (k, k + 1))));
+
+        let empty = TreapMap::<u32, u32>::from_sorted_vec(Vec::new());
+        assert!(empty.is_empty());
+
+        let dup = TreapMap::try_from_sorted_iter(vec![(1, ()), (2, ()), (2, ())]);
+        assert_eq!(dup.map(|t| t.len()).unwrap_err().index(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "not sorted")]
+    fn from_sorted_unsorted() {
+        TreapMap::from_sorted_vec(vec![(2, ()), (1, ())]);
+    }
+
+    #[test]
+    fn explicit_priorities() {
+        use priority;
+
+        // Priorities decreasing with the key make every node the right child of its predecessor
+        let mut t = TreapMap::new_with_priorities(priority::from_fn(|k: &u32| u64::from(!*k)));
+        t.extend((0..100).rev().map(|k| (k, ())));
+        t.check_invariants();
+        let mut depth = 0;
+        let mut cur = t.tree().1.as_deref();
+        while let Some(node) = cur {
+            depth += 1;
+            assert!(node.left.is_none());
+            cur = node.right.as_deref();
+        }
+        assert_eq!(depth, 100);
+    }
+
+    // Keys of the treap in preorder, which determines its shape
+    fn preorder<K: Ord + Clone, V, P: PrioritySource<K>>(t: &TreapMap<K, V, P>) -> Vec<K> {
+        fn walk<K: Clone, V>(node: Option<&Node<K, V, Boxed, ()>>, out: &mut Vec<K>) {
+            if let Some(node) = node {
+                out.push(node.key.clone());
+                walk(node.left.as_deref(), out);
+                walk(node.right.as_deref(), out);
+            }
+        }
+        let mut out = Vec::new();
+        walk(t.tree().1.as_deref(), &mut out);
+        out
+    }
+
+    #[test]
+    fn hashed_priorities_history_independent() {
+        let mut a = TreapMap::with_hashed_priorities(1234);
+        a.extend((0..500).map(|k| (k, ())));
+
+        // Different insertion order, extra keys removed one by one and by range
+        let mut b = TreapMap::with_hashed_priorities(1234);
+        b.extend((0..700).rev().map(|k| (k, ())));
+        for k in 600..700 {
+            b.remove(&k);
+        }
+        b.drain_range(500..600).for_each(drop);
+        b.check_invariants();
+        assert_eq!(preorder(&a), preorder(&b));
+
+        let c = TreapMap::try_from_sorted_iter_with_priorities(
+            (0..500).map(|k| (k, ())),
+            HashedPriorities::new(1234),
+        )
+        .unwrap();
+        assert_eq!(preorder(&a), preorder(&c));
+
+        let mut d = TreapMap::with_hashed_priorities(1234);
+        d.extend((0..1000).map(|k| (k, ())));
+        d.retain(|k, _| *k < 500);
+        assert_eq!(preorder(&a), preorder(&d));
+        let e = d.split_off(&250);
+        d.append(&mut e.clone());
+        assert_eq!(preorder(&a), preorder(&d));
+    }
+
+    #[test]
+    fn structural_eq_ord_hash() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash<T: Hash>(t: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            t.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a: TreapMap<_, _> = TreapMap::from_iter((0..100).map(|k| (k, k * 2)));
+        let b: TreapMap<_, _> = TreapMap::from_iter((0..100).rev().map(|k| (k, k * 2)));
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        let mut c = b.clone();
+        c.insert(50, 0);
+        assert_ne!(a, c);
+        assert!(c < a);
+        c.remove(&99);
+        c.insert(50, 100);
+        assert!(c < a);
+        c.insert(99, 198);
+        assert_eq!(a, c);
+        assert_eq!(a.cmp(&c), ::std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn debug_output() {
+        let t: TreapMap<_, _> = TreapMap::from_iter(vec![(2, "b"), (1, "a"), (3, "c")]);
+        assert_eq!(format!("{:?}", t), r#"{1: "a", 2: "b", 3: "c"}"#);
+        assert_eq!(format!("{:?}", TreapMap::<u8, u8>::new()), "{}");
+
+        let tree = format!("{:?}", t.debug_tree());
+        assert_eq!(tree.lines().count(), 3);
+        assert!(tree.contains(r#"2: "b""#));
+        assert_eq!(
+            format!("{:?}", TreapMap::<u8, u8>::new().debug_tree()),
+            "(empty)\n"
+        );
+    }
+
+    #[test]
+    fn degenerate_tree() {
+        use priority;
+
+        // Increasing priorities for increasing keys turn the treap into a path of left children
+        // that would overflow the stack with recursive operations
+        let n = 200_000;
+        let mut t = TreapMap::new_with_priorities(priority::from_fn(|k: &u64| *k));
+        t.extend((0..n).map(|k| (k, k)));
+        assert_eq!(t.len(), n as usize);
+        assert_eq!(t.get(&0), Some(&0));
+        assert_eq!(t.insert(1, 10), Some(1));
+        assert_eq!(t.get(&1), Some(&10));
+        assert_eq!(t.remove(&0), Some(0));
+        assert_eq!(t.remove(&0), None);
+        assert_eq!(t.range(..5).count(), 4);
+        assert_eq!(t.drain_range(n / 2..).count(), (n / 2) as usize);
+
+        drop(t);
+    }
+
+    #[test]
+    fn degenerate_tree_set_operations() {
+        use node::Node;
+        use priority;
+
+        fn key_priority(k: &u64) -> u64 {
+            *k
+        }
+
+        // Two interleaved paths of 150,000 nodes each, and set operations that combine them
+        let n = 300_000;
+        let path = |keys: &mut dyn Iterator<Item = u64>| {
+            let mut t = TreapMap::new_with_priorities(priority::from_fn(key_priority));
+            t.extend(keys.map(|k| (k, ())));
+            t
+        };
+        let evens = || path(&mut (0..n).filter(|k| k % 2 == 0));
+        let odds = || path(&mut (0..n).filter(|k| k % 2 == 1));
+
+        let mut all = evens();
+        all.append(&mut odds());
+        assert_eq!(all.len(), n as usize);
+        assert!(Node::is_subset(evens().tree(), all.tree()));
+        assert!(!Node::is_subset(all.tree(), evens().tree()));
+        assert!(Node::is_disjoint(evens().tree(), odds().tree()));
+        assert!(!Node::is_disjoint(evens().tree(), all.tree()));
+
+        let mut t = all.clone();
+        t.combine(evens(), Node::intersection);
+        assert_eq!(t, evens());
+        let mut t = all.clone();
+        t.combine(evens(), Node::difference);
+        assert_eq!(t, odds());
+        let mut t = evens();
+        t.combine(all.clone(), Node::symmetric_difference);
+        assert_eq!(t, odds());
+
+        // The output grows with the square of the depth, so use a shorter path
+        let shape = format!("{:?}", path(&mut (0..5000)).debug_tree());
+        assert_eq!(shape.lines().count(), 5000);
+        assert!(shape.ends_with(&format!("{}L 0: ()\n", "  ".repeat(4999))));
+    }
+
+    #[test]
+    fn drop_degenerate_tree() {
+        use priority;
+
+        let n = 500_000;
+        let path = || {
+            let mut t = TreapMap::new_with_priorities(priority::from_fn(|k: &u64| *k));
+            t.extend((0..n).map(|k| (k, ())));
+            t
+        };
+        drop(path());
+        path().clear();
+        let mut iter = path().into_iter();
+        iter.next();
+        drop(iter);
+        let mut iter = path().into_iter_ordered();
+        iter.next();
+        drop(iter);
+    }
 }
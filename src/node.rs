@@ -1,168 +1,1242 @@
+use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::mem;
+use std::ops::Bound;
 
-#[derive(Debug, Clone)]
-pub struct Node<K, V> {
+use aggregate::Aggregate;
+
+/// Where the nodes of a tree are kept. Nodes refer to their children through handles issued by
+/// the store, and a handle owns its node the way a `Box` does: the node can only be reached
+/// through it and is released when the handle is given back with `take` or `drop_tree`. The
+/// algorithms below move handles between nodes and only borrow a node while reading or updating
+/// it, so they work the same whether each node is a separate allocation or a slot in a vector.
+///
+/// The methods that hand out mutable borrows of values mark every node they borrow, and the
+/// nodes above it, as changed, so that their summaries are computed again.
+pub trait Store<K, V, A: Aggregate<K, V>>: Default + Sized {
+    /// An owning reference to a node in the store.
+    type Handle;
+
+    /// A mutable iterator over the entries of a tree in no particular order.
+    type IterMut<'a>: ExactSizeIterator<Item = (&'a K, &'a mut V)>
+    where
+        Self: 'a,
+        K: 'a,
+        V: 'a,
+        A: 'a;
+
+    /// A mutable iterator over consecutive entries of a tree in key order.
+    type OrderedIterMut<'a>: ExactSizeIterator<Item = (&'a K, &'a mut V)>
+    where
+        Self: 'a,
+        K: 'a,
+        V: 'a,
+        A: 'a;
+
+    /// Borrow the node behind a handle.
+    fn node<'a>(&'a self, handle: &'a Self::Handle) -> &'a Node<K, V, Self, A>;
+
+    /// Mutably borrow the node behind a handle. A store that shares nodes between trees gives the
+    /// handle a private copy of the node first.
+    fn node_mut<'a>(&'a mut self, handle: &'a mut Self::Handle) -> &'a mut Node<K, V, Self, A>;
+
+    /// Put a node into the store.
+    fn alloc(&mut self, node: Node<K, V, Self, A>) -> Self::Handle;
+
+    /// Remove a node from the store and return it. Its children stay in the store.
+    fn take(&mut self, handle: Self::Handle) -> Node<K, V, Self, A>;
+
+    /// Release the nodes of a subtree without recursion.
+    fn drop_tree(&mut self, subtree: Link<K, V, Self, A>);
+
+    /// Walk down from the root of the subtree, going to the left child where `step` returns
+    /// `Some(true)` and to the right child where it returns `Some(false)`, and mutably borrow the
+    /// node where it returns `None`. Returns `None` if the walk falls off the tree. The nodes on
+    /// the way are marked as changed.
+    fn descend_mut<'a, F>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Self, A>,
+        step: F,
+    ) -> Option<&'a mut Node<K, V, Self, A>>
+    where
+        F: FnMut(&Node<K, V, Self, A>) -> Option<bool>;
+
+    /// Mutably iterate over the entries of `subtree`, which must be the only tree in the store.
+    fn iter_mut<'a>(&'a mut self, subtree: &'a mut Link<K, V, Self, A>) -> Self::IterMut<'a>;
+
+    /// Mutably iterate in key order over `len` entries of the subtree, starting with the entry
+    /// at position `skip`.
+    fn ordered_mut<'a>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Self, A>,
+        skip: usize,
+        len: usize,
+    ) -> Self::OrderedIterMut<'a>;
+
+    /// Move a subtree from `other` into this store.
+    fn adopt(&mut self, other: &mut Self, subtree: Link<K, V, Self, A>) -> Link<K, V, Self, A>;
+
+    /// Copy the store together with `subtree`, which must be the only tree in it.
+    fn clone_tree(&self, subtree: &Link<K, V, Self, A>) -> (Self, Link<K, V, Self, A>)
+    where
+        K: Clone,
+        V: Clone;
+}
+
+/// A store whose handles lead to their nodes without going through the store, like `Box` and
+/// `Arc`. Borrows of several nodes can then be held at once by splitting the borrow of a node
+/// into borrows of its fields.
+pub trait Linked<K, V, A: Aggregate<K, V>>: Store<K, V, A> {
+    fn get(handle: &Self::Handle) -> &Node<K, V, Self, A>;
+
+    fn get_mut(handle: &mut Self::Handle) -> &mut Node<K, V, Self, A>;
+}
+
+pub type Link<K, V, S, A> = Option<<S as Store<K, V, A>>::Handle>;
+
+/// The nodes of a tree split at a key: those below it, the one with the key and those above it.
+pub type Parts<K, V, S, A> = (Link<K, V, S, A>, Link<K, V, S, A>, Link<K, V, S, A>);
+
+/// The nodes of a tree split in two.
+pub type Halves<K, V, S, A> = (Link<K, V, S, A>, Link<K, V, S, A>);
+
+pub struct Node<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> {
     pub key: K,
     pub value: V,
-    priority: f64, // TODO: use a u64! much faster!
-    pub left: Option<Box<Node<K, V>>>,
-    pub right: Option<Box<Node<K, V>>>,
+    priority: u64,
+    // Number of nodes in the subtree rooted at this node, which is its `Count` summary
+    pub size: usize,
+    // Summary of the entries in the subtree rooted at this node, or `None` if a value in the
+    // subtree may have been changed since. The nodes below a node with a summary have one too.
+    summary: Option<A::Summary>,
+    pub left: Link<K, V, S, A>,
+    pub right: Link<K, V, S, A>,
+}
+
+// The next step of a set operation on a pair of subtrees: either the finished result, or the root
+// of the result, if it keeps one, with the pairs of subtrees that go to its left and right.
+enum Step<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> {
+    Done(Link<K, V, S, A>),
+    Split(Link<K, V, S, A>, Pairs<K, V, S, A>),
 }
 
-enum RemovalCases {
-    RemoveNode,
-    RotateLeft,
-    RotateRight,
+type Pairs<K, V, S, A> = (
+    (Link<K, V, S, A>, Link<K, V, S, A>),
+    (Link<K, V, S, A>, Link<K, V, S, A>),
+);
+
+enum Work<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> {
+    Pair(Link<K, V, S, A>, Link<K, V, S, A>),
+    Attach(Link<K, V, S, A>),
 }
 
-impl<K: Ord, V> Node<K, V> {
-    pub fn new(key: K, value: V, priority: f64) -> Node<K, V> {
+impl<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> Node<K, V, S, A> {
+    pub fn new(key: K, value: V, priority: u64) -> Node<K, V, S, A> {
+        let summary = Some(A::summarize(&key, &value));
         Node {
             key,
             value,
             priority,
+            size: 1,
+            summary,
             left: None,
             right: None,
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
-        match key.cmp(&self.key) {
-            Ordering::Equal => Some(&self.value),
-            Ordering::Less => self.left.as_ref().and_then(|n| n.get(key)),
-            Ordering::Greater => self.right.as_ref().and_then(|n| n.get(key)),
+    /// The node a link points to.
+    #[inline]
+    pub fn at<'a>(store: &'a S, link: &'a Link<K, V, S, A>) -> Option<&'a Node<K, V, S, A>> {
+        link.as_ref().map(|handle| store.node(handle))
+    }
+
+    /// Number of nodes in the subtree.
+    #[inline]
+    pub fn size(store: &S, subtree: &Link<K, V, S, A>) -> usize {
+        Node::at(store, subtree).map_or(0, |n| n.size)
+    }
+
+    /// The summary of the entries in the subtree. Where a summary is missing it is computed from
+    /// the nodes below, without recursion.
+    pub fn summary(store: &S, subtree: &Link<K, V, S, A>) -> A::Summary {
+        // Each node without a summary is visited twice: first to visit its children, then to
+        // combine their summaries, which are on top of `done` by then
+        let mut work = vec![(Node::at(store, subtree), false)];
+        let mut done = Vec::new();
+        while let Some((node, children_done)) = work.pop() {
+            let node = match node {
+                None => {
+                    done.push(A::identity());
+                    continue;
+                }
+                Some(node) => node,
+            };
+            if children_done {
+                let right = done.pop().unwrap();
+                let left = done.pop().unwrap();
+                let entry = A::summarize(&node.key, &node.value);
+                done.push(A::combine(&A::combine(&left, &entry), &right));
+            } else if let Some(ref summary) = node.summary {
+                done.push(summary.clone());
+            } else {
+                work.push((Some(node), true));
+                work.push((Node::at(store, &node.right), false));
+                work.push((Node::at(store, &node.left), false));
+            }
         }
+        done.pop().unwrap()
     }
 
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        match key.cmp(&self.key) {
-            Ordering::Equal => Some(&mut self.value),
-            Ordering::Less => self.left.as_mut().and_then(|n| n.get_mut(key)),
-            Ordering::Greater => self.right.as_mut().and_then(|n| n.get_mut(key)),
+    /// Note that the value of this node may change, which invalidates its summary. The caller
+    /// must mark the nodes above it as well.
+    #[inline]
+    pub fn mark_changed(&mut self) {
+        // Summaries without data cannot go stale
+        if mem::size_of::<A::Summary>() != 0 {
+            self.summary = None;
         }
     }
 
-    pub fn insert_or_replace(subtree: &mut Option<Box<Node<K, V>>>, new: Node<K, V>) -> Option<V> {
-        match *subtree {
-            None => {
-                mem::replace(subtree, Some(Box::new(new)));
-                None
+    // Recompute the size and summary of a node from its entry and its children. The summary
+    // stays missing while one of the children is missing its summary.
+    #[inline]
+    fn update(store: &mut S, handle: &mut S::Handle) {
+        let node = store.node(handle);
+        let (left, right) = (Node::at(store, &node.left), Node::at(store, &node.right));
+        let size = 1 + left.map_or(0, |n| n.size) + right.map_or(0, |n| n.size);
+        let cached = |child: Option<&Node<K, V, S, A>>| match child {
+            None => Some(A::identity()),
+            Some(child) => child.summary.clone(),
+        };
+        let summary = match (cached(left), cached(right)) {
+            (Some(l), Some(r)) => {
+                let entry = A::summarize(&node.key, &node.value);
+                Some(A::combine(&A::combine(&l, &entry), &r))
             }
-            Some(ref mut node) => node.insert(new),
+            _ => None,
+        };
+        let node = store.node_mut(handle);
+        node.size = size;
+        node.summary = summary;
+    }
+
+    #[inline]
+    fn set_left(store: &mut S, handle: &mut S::Handle, left: Link<K, V, S, A>) {
+        store.node_mut(handle).left = left;
+        Node::update(store, handle);
+    }
+
+    #[inline]
+    fn set_right(store: &mut S, handle: &mut S::Handle, right: Link<K, V, S, A>) {
+        store.node_mut(handle).right = right;
+        Node::update(store, handle);
+    }
+
+    #[inline]
+    fn set_children(
+        store: &mut S,
+        handle: &mut S::Handle,
+        left: Link<K, V, S, A>,
+        right: Link<K, V, S, A>,
+    ) {
+        let node = store.node_mut(handle);
+        node.left = left;
+        node.right = right;
+        Node::update(store, handle);
+    }
+
+    // Detach both children of a node.
+    #[inline]
+    fn take_children(store: &mut S, handle: &mut S::Handle) -> Halves<K, V, S, A> {
+        let node = store.node_mut(handle);
+        (node.left.take(), node.right.take())
+    }
+
+    /// The node with the smallest key in the subtree.
+    pub fn leftmost<'a>(store: &'a S, mut node: &'a Node<K, V, S, A>) -> &'a Node<K, V, S, A> {
+        while let Some(left) = Node::at(store, &node.left) {
+            node = left;
         }
+        node
     }
 
-    pub fn insert(&mut self, node: Node<K, V>) -> Option<V> {
-        match node.key.cmp(&self.key) {
-            Ordering::Equal => {
-                if self.priority < node.priority {
-                    self.priority = node.priority;
+    /// The node with the largest key in the subtree.
+    pub fn rightmost<'a>(store: &'a S, mut node: &'a Node<K, V, S, A>) -> &'a Node<K, V, S, A> {
+        while let Some(right) = Node::at(store, &node.right) {
+            node = right;
+        }
+        node
+    }
+
+    /// The node with the `index`th smallest key in the subtree, counting from zero.
+    pub fn select<'a>(
+        store: &'a S,
+        subtree: &'a Link<K, V, S, A>,
+        mut index: usize,
+    ) -> Option<&'a Node<K, V, S, A>> {
+        let mut node = Node::at(store, subtree)?;
+        loop {
+            let left = Node::size(store, &node.left);
+            match index.cmp(&left) {
+                Ordering::Less => node = Node::at(store, &node.left)?,
+                Ordering::Equal => return Some(node),
+                Ordering::Greater => {
+                    index -= left + 1;
+                    node = Node::at(store, &node.right)?;
                 }
-                Some(mem::replace(&mut self.value, node.value))
             }
-            Ordering::Less => {
-                let old_value = Node::insert_or_replace(&mut self.left, node);
-                if self.is_heap_property_violated(&self.left) {
-                    self.right_rotate();
+        }
+    }
+
+    /// Merge two subtrees where every key in `left` is less than every key in `right`.
+    pub fn merge(
+        store: &mut S,
+        left: Link<K, V, S, A>,
+        right: Link<K, V, S, A>,
+    ) -> Link<K, V, S, A> {
+        // Walk down the right spine of `left` and the left spine of `right`, always descending
+        // into the tree whose root has the lower priority. The nodes passed on the way form the
+        // path from the root of the merged tree and are reattached from the bottom up.
+        let mut path = Vec::new();
+        let (mut left, mut right) = (left, right);
+        let mut merged = loop {
+            match (left, right) {
+                (None, r) => break r,
+                (l, None) => break l,
+                (Some(mut l), Some(mut r)) => {
+                    if store.node(&l).priority >= store.node(&r).priority {
+                        left = store.node_mut(&mut l).right.take();
+                        right = Some(r);
+                        path.push((l, true));
+                    } else {
+                        left = Some(l);
+                        right = store.node_mut(&mut r).left.take();
+                        path.push((r, false));
+                    }
                 }
-                old_value
             }
-            Ordering::Greater => {
-                let old_value = Node::insert_or_replace(&mut self.right, node);
-                if self.is_heap_property_violated(&self.right) {
-                    self.left_rotate();
-                }
-                old_value
+        };
+        while let Some((mut node, from_left)) = path.pop() {
+            if from_left {
+                Node::set_right(store, &mut node, merged);
+            } else {
+                Node::set_left(store, &mut node, merged);
             }
+            merged = Some(node);
+        }
+        merged
+    }
+}
+
+impl<K: Clone, V: Clone, S: Store<K, V, A>, A: Aggregate<K, V>> Node<K, V, S, A> {
+    /// A copy of the node without its children, for the same or another store. The copy keeps
+    /// the size and summary of the original, so it must be given copies of the same children.
+    pub fn copy_detached<T: Store<K, V, A>>(&self) -> Node<K, V, T, A> {
+        Node {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            priority: self.priority,
+            size: self.size,
+            summary: self.summary.clone(),
+            left: None,
+            right: None,
+        }
+    }
+}
+
+/// Copies the entry of the node and the handles of its children.
+impl<K: Clone, V: Clone, S: Store<K, V, A>, A: Aggregate<K, V>> Clone for Node<K, V, S, A>
+where
+    S::Handle: Clone,
+{
+    fn clone(&self) -> Node<K, V, S, A> {
+        Node {
+            left: self.left.clone(),
+            right: self.right.clone(),
+            ..self.copy_detached()
         }
     }
+}
 
-    pub fn remove(subtree: &mut Option<Box<Node<K, V>>>, key: &K) -> Option<V> {
-        {
-            let node = match *subtree {
-                None => return None,
-                Some(ref mut n) => n,
+impl<K: Ord, V, S: Store<K, V, A>, A: Aggregate<K, V>> Node<K, V, S, A> {
+    pub fn get<'a, Q: ?Sized + Ord>(
+        store: &'a S,
+        mut subtree: Option<&'a Node<K, V, S, A>>,
+        key: &Q,
+    ) -> Option<&'a Node<K, V, S, A>>
+    where
+        K: Borrow<Q>,
+    {
+        while let Some(node) = subtree {
+            subtree = match key.cmp(node.key.borrow()) {
+                Ordering::Equal => return Some(node),
+                Ordering::Less => Node::at(store, &node.left),
+                Ordering::Greater => Node::at(store, &node.right),
             };
-            match key.cmp(&node.key) {
-                Ordering::Less => return Node::remove(&mut node.left, key),
-                Ordering::Greater => return Node::remove(&mut node.right, key),
-                Ordering::Equal => {}
+        }
+        None
+    }
+
+    /// Number of keys in the subtree that are less than `key`.
+    pub fn rank<Q: ?Sized + Ord>(store: &S, subtree: &Link<K, V, S, A>, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        Node::count_prefix(store, subtree, |k| k.borrow() < key)
+    }
+
+    /// Number of keys in the subtree for which `in_prefix` holds. The predicate must hold for a
+    /// prefix of the keys in sorted order.
+    pub fn count_prefix<F: Fn(&K) -> bool>(
+        store: &S,
+        subtree: &Link<K, V, S, A>,
+        in_prefix: F,
+    ) -> usize {
+        let mut count = 0;
+        let mut cur = Node::at(store, subtree);
+        while let Some(node) = cur {
+            if in_prefix(&node.key) {
+                count += Node::size(store, &node.left) + 1;
+                cur = Node::at(store, &node.right);
+            } else {
+                cur = Node::at(store, &node.left);
             }
         }
-        Node::rotate_down(subtree)
+        count
     }
 
-    fn rotate_down(subtree: &mut Option<Box<Node<K, V>>>) -> Option<V> {
-        let case = match *subtree {
-            None => return None,
-            Some(ref root) => match (&root.left, &root.right) {
-                (&None, &None) => RemovalCases::RemoveNode,
-                (&Some(ref left), &Some(ref right)) => {
-                    if left.priority >= right.priority {
-                        RemovalCases::RotateRight
-                    } else {
-                        RemovalCases::RotateLeft
+    /// The node with the largest key for which `in_prefix` holds. The predicate must hold for a
+    /// prefix of the keys in sorted order.
+    pub fn last_in_prefix<'a, F: Fn(&K) -> bool>(
+        store: &'a S,
+        subtree: &'a Link<K, V, S, A>,
+        in_prefix: F,
+    ) -> Option<&'a Node<K, V, S, A>> {
+        let mut found = None;
+        let mut cur = Node::at(store, subtree);
+        while let Some(node) = cur {
+            if in_prefix(&node.key) {
+                found = Some(node);
+                cur = Node::at(store, &node.right);
+            } else {
+                cur = Node::at(store, &node.left);
+            }
+        }
+        found
+    }
+
+    /// The node with the smallest key for which `in_prefix` does not hold. The predicate must
+    /// hold for a prefix of the keys in sorted order.
+    pub fn first_after_prefix<'a, F: Fn(&K) -> bool>(
+        store: &'a S,
+        subtree: &'a Link<K, V, S, A>,
+        in_prefix: F,
+    ) -> Option<&'a Node<K, V, S, A>> {
+        let mut found = None;
+        let mut cur = Node::at(store, subtree);
+        while let Some(node) = cur {
+            if in_prefix(&node.key) {
+                cur = Node::at(store, &node.right);
+            } else {
+                found = Some(node);
+                cur = Node::at(store, &node.left);
+            }
+        }
+        found
+    }
+
+    /// Split the subtree into the nodes with keys less than `key`, the node with a key equal to
+    /// `key` if any, and the nodes with keys greater than `key`.
+    pub fn split<Q: ?Sized + Ord>(
+        store: &mut S,
+        subtree: Link<K, V, S, A>,
+        key: &Q,
+    ) -> Parts<K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        Node::split_with(store, subtree, |_, node| key.cmp(node.key.borrow()))
+    }
+
+    /// Split the subtree like `split`, where `order` compares the key to split at with the key of
+    /// a node. The key can then be one of the keys in the store.
+    fn split_with<F>(store: &mut S, subtree: Link<K, V, S, A>, mut order: F) -> Parts<K, V, S, A>
+    where
+        F: FnMut(&S, &Node<K, V, S, A>) -> Ordering,
+    {
+        // Walk down the search path and collect the nodes that end up in the left tree, which
+        // need a new right child, and the nodes that end up in the right tree, which need a new
+        // left child. Then reattach them from the bottom up.
+        let mut to_left = Vec::new();
+        let mut to_right = Vec::new();
+        let mut cur = subtree;
+        let (mut left, mut mid, mut right) = (None, None, None);
+        while let Some(mut node) = cur {
+            match order(store, store.node(&node)) {
+                Ordering::Equal => {
+                    let (l, r) = Node::take_children(store, &mut node);
+                    left = l;
+                    right = r;
+                    Node::update(store, &mut node);
+                    mid = Some(node);
+                    break;
+                }
+                Ordering::Less => {
+                    cur = store.node_mut(&mut node).left.take();
+                    to_right.push(node);
+                }
+                Ordering::Greater => {
+                    cur = store.node_mut(&mut node).right.take();
+                    to_left.push(node);
+                }
+            }
+        }
+        while let Some(mut node) = to_left.pop() {
+            Node::set_right(store, &mut node, left);
+            left = Some(node);
+        }
+        while let Some(mut node) = to_right.pop() {
+            Node::set_left(store, &mut node, right);
+            right = Some(node);
+        }
+        (left, mid, right)
+    }
+
+    /// Split the subtree into the nodes below the lower bound `start` and the remaining nodes.
+    pub fn split_before<Q: ?Sized + Ord>(
+        store: &mut S,
+        subtree: Link<K, V, S, A>,
+        start: Bound<&Q>,
+    ) -> Halves<K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        match start {
+            Bound::Unbounded => (None, subtree),
+            Bound::Included(key) => {
+                let (left, mid, right) = Node::split(store, subtree, key);
+                (left, Node::merge(store, mid, right))
+            }
+            Bound::Excluded(key) => {
+                let (left, mid, right) = Node::split(store, subtree, key);
+                (Node::merge(store, left, mid), right)
+            }
+        }
+    }
+
+    /// Split the subtree into the nodes within the upper bound `end` and the nodes above it.
+    pub fn split_after<Q: ?Sized + Ord>(
+        store: &mut S,
+        subtree: Link<K, V, S, A>,
+        end: Bound<&Q>,
+    ) -> Halves<K, V, S, A>
+    where
+        K: Borrow<Q>,
+    {
+        match end {
+            Bound::Unbounded => (subtree, None),
+            Bound::Included(key) => {
+                let (left, mid, right) = Node::split(store, subtree, key);
+                (Node::merge(store, left, mid), right)
+            }
+            Bound::Excluded(key) => {
+                let (left, mid, right) = Node::split(store, subtree, key);
+                (left, Node::merge(store, mid, right))
+            }
+        }
+    }
+
+    /// Build a subtree from detached nodes given in increasing key order, keeping their
+    /// priorities. Runs in linear time by maintaining the right spine of the tree built so far.
+    /// Fails with the index of the first node whose key is not greater than the previous one.
+    pub fn from_sorted_nodes<I>(store: &mut S, nodes: I) -> Result<Link<K, V, S, A>, usize>
+    where
+        I: IntoIterator<Item = S::Handle>,
+    {
+        let mut spine: Vec<S::Handle> = Vec::new();
+        for (index, mut node) in nodes.into_iter().enumerate() {
+            // The previous node is always on top of the spine
+            if let Some(top) = spine.last() {
+                if store.node(top).key >= store.node(&node).key {
+                    store.drop_tree(Some(node));
+                    while let Some(top) = spine.pop() {
+                        store.drop_tree(Some(top));
                     }
+                    return Err(index);
                 }
-                (&Some(_), &None) => RemovalCases::RotateRight,
-                (&None, &Some(_)) => RemovalCases::RotateLeft,
-            },
+            }
+            let priority = store.node(&node).priority;
+            let mut left = None;
+            while spine
+                .last()
+                .map_or(false, |top| store.node(top).priority < priority)
+            {
+                let mut top = spine.pop().unwrap();
+                Node::set_right(store, &mut top, left);
+                left = Some(top);
+            }
+            Node::set_children(store, &mut node, left, None);
+            spine.push(node);
+        }
+        let mut subtree = None;
+        while let Some(mut top) = spine.pop() {
+            Node::set_right(store, &mut top, subtree);
+            subtree = Some(top);
+        }
+        Ok(subtree)
+    }
+
+    /// Combine two subtrees with arbitrary keys into one. When a key is present in both subtrees
+    /// the node from `b` is kept.
+    pub fn union(store: &mut S, a: Link<K, V, S, A>, b: Link<K, V, S, A>) -> Link<K, V, S, A> {
+        Node::combine(store, a, b, |store, a, b| match (a, b) {
+            (None, b) => Step::Done(b),
+            (a, None) => Step::Done(a),
+            (Some(mut a), Some(mut b)) => {
+                if store.node(&a).priority >= store.node(&b).priority {
+                    let (left, mid, right) =
+                        Node::split_with(store, Some(b), |s, n| s.node(&a).key.cmp(&n.key));
+                    if let Some(mid) = mid {
+                        store.node_mut(&mut a).value = store.take(mid).value;
+                    }
+                    let (a_left, a_right) = Node::take_children(store, &mut a);
+                    Step::Split(Some(a), ((a_left, left), (a_right, right)))
+                } else {
+                    let (left, mid, right) =
+                        Node::split_with(store, Some(a), |s, n| s.node(&b).key.cmp(&n.key));
+                    store.drop_tree(mid);
+                    let (b_left, b_right) = Node::take_children(store, &mut b);
+                    Step::Split(Some(b), ((left, b_left), (right, b_right)))
+                }
+            }
+        })
+    }
+
+    /// Keep the nodes from `a` whose keys are also in `b`.
+    pub fn intersection(
+        store: &mut S,
+        a: Link<K, V, S, A>,
+        b: Link<K, V, S, A>,
+    ) -> Link<K, V, S, A> {
+        Node::combine(store, a, b, |store, a, b| match (a, b) {
+            (None, b) | (b, None) => {
+                store.drop_tree(b);
+                Step::Done(None)
+            }
+            (Some(mut a), Some(mut b)) => {
+                if store.node(&a).priority >= store.node(&b).priority {
+                    let (left, mid, right) =
+                        Node::split_with(store, Some(b), |s, n| s.node(&a).key.cmp(&n.key));
+                    let (a_left, a_right) = Node::take_children(store, &mut a);
+                    let pairs = ((a_left, left), (a_right, right));
+                    let root = match mid {
+                        Some(mid) => {
+                            store.drop_tree(Some(mid));
+                            Some(a)
+                        }
+                        None => {
+                            store.drop_tree(Some(a));
+                            None
+                        }
+                    };
+                    Step::Split(root, pairs)
+                } else {
+                    let (left, mid, right) =
+                        Node::split_with(store, Some(a), |s, n| s.node(&b).key.cmp(&n.key));
+                    let (b_left, b_right) = Node::take_children(store, &mut b);
+                    let pairs = ((left, b_left), (right, b_right));
+                    let root = match mid {
+                        Some(mid) => {
+                            // Keep the position and priority of `b` but the entry of `a`
+                            let mid = store.take(mid);
+                            let node = store.node_mut(&mut b);
+                            node.key = mid.key;
+                            node.value = mid.value;
+                            Some(b)
+                        }
+                        None => {
+                            store.drop_tree(Some(b));
+                            None
+                        }
+                    };
+                    Step::Split(root, pairs)
+                }
+            }
+        })
+    }
+
+    /// Keep the nodes from `a` whose keys are not in `b`.
+    pub fn difference(store: &mut S, a: Link<K, V, S, A>, b: Link<K, V, S, A>) -> Link<K, V, S, A> {
+        Node::combine(store, a, b, |store, a, b| match (a, b) {
+            (None, b) => {
+                store.drop_tree(b);
+                Step::Done(None)
+            }
+            (a, None) => Step::Done(a),
+            (Some(mut a), b) => {
+                let (left, mid, right) =
+                    Node::split_with(store, b, |s, n| s.node(&a).key.cmp(&n.key));
+                let (a_left, a_right) = Node::take_children(store, &mut a);
+                let pairs = ((a_left, left), (a_right, right));
+                if mid.is_some() {
+                    store.drop_tree(mid);
+                    store.drop_tree(Some(a));
+                    Step::Split(None, pairs)
+                } else {
+                    Step::Split(Some(a), pairs)
+                }
+            }
+        })
+    }
+
+    /// Keep the nodes from `a` and `b` whose keys are in only one of the subtrees.
+    pub fn symmetric_difference(
+        store: &mut S,
+        a: Link<K, V, S, A>,
+        b: Link<K, V, S, A>,
+    ) -> Link<K, V, S, A> {
+        Node::combine(store, a, b, |store, a, b| match (a, b) {
+            (None, b) => Step::Done(b),
+            (a, None) => Step::Done(a),
+            (Some(a), Some(b)) => {
+                let (mut root, other) = if store.node(&a).priority >= store.node(&b).priority {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                let (left, mid, right) =
+                    Node::split_with(store, Some(other), |s, n| s.node(&root).key.cmp(&n.key));
+                let (root_left, root_right) = Node::take_children(store, &mut root);
+                let pairs = ((root_left, left), (root_right, right));
+                if mid.is_some() {
+                    store.drop_tree(mid);
+                    store.drop_tree(Some(root));
+                    Step::Split(None, pairs)
+                } else {
+                    Step::Split(Some(root), pairs)
+                }
+            }
+        })
+    }
+
+    // Run one of the set operations without recursion. `step` either finishes a pair of subtrees
+    // or picks the root of their result and splits the rest into a pair of left subtrees and a
+    // pair of right subtrees. A pair is only combined once the pairs before it are done, so the
+    // results of the left and right pairs are on top of `done` when their root is attached.
+    fn combine<F>(
+        store: &mut S,
+        a: Link<K, V, S, A>,
+        b: Link<K, V, S, A>,
+        mut step: F,
+    ) -> Link<K, V, S, A>
+    where
+        F: FnMut(&mut S, Link<K, V, S, A>, Link<K, V, S, A>) -> Step<K, V, S, A>,
+    {
+        let mut work: Vec<Work<K, V, S, A>> = vec![Work::Pair(a, b)];
+        let mut done: Vec<Link<K, V, S, A>> = Vec::new();
+        while let Some(item) = work.pop() {
+            match item {
+                Work::Pair(a, b) => match step(store, a, b) {
+                    Step::Done(subtree) => done.push(subtree),
+                    Step::Split(root, (left, right)) => {
+                        work.push(Work::Attach(root));
+                        work.push(Work::Pair(right.0, right.1));
+                        work.push(Work::Pair(left.0, left.1));
+                    }
+                },
+                Work::Attach(root) => {
+                    let right = done.pop().unwrap();
+                    let left = done.pop().unwrap();
+                    done.push(match root {
+                        Some(mut root) => {
+                            Node::set_children(store, &mut root, left, right);
+                            Some(root)
+                        }
+                        // The root was dropped from the result
+                        None => Node::merge(store, left, right),
+                    });
+                }
+            }
+        }
+        done.pop().unwrap()
+    }
+
+    /// Compute the missing summaries in the subtree again. Only the nodes without a summary are
+    /// visited, which are those on the paths to values that may have changed.
+    pub fn refresh(store: &mut S, subtree: &mut Link<K, V, S, A>) {
+        if Node::at(store, subtree).map_or(true, |n| n.summary.is_some()) {
+            return;
+        }
+        // Like a set operation with an empty tree that detaches the children of every node without
+        // a summary, so that reattaching them recomputes it
+        let stale = subtree.take();
+        *subtree = Node::combine(store, stale, None, |store, a, _| match a {
+            Some(mut node) if store.node(&node).summary.is_none() => {
+                let (left, right) = Node::take_children(store, &mut node);
+                Step::Split(Some(node), ((left, None), (right, None)))
+            }
+            a => Step::Done(a),
+        });
+    }
+
+    /// The root of the smallest subtree that holds all the nodes with keys strictly between `lo`
+    /// and `hi`, if there are any such nodes. The subtree may contain nodes outside the bounds.
+    fn restrict<'a>(
+        store: &'a S,
+        mut subtree: Option<&'a Node<K, V, S, A>>,
+        lo: Option<&K>,
+        hi: Option<&K>,
+    ) -> Option<&'a Node<K, V, S, A>> {
+        while let Some(node) = subtree {
+            if lo.map_or(false, |lo| node.key <= *lo) {
+                subtree = Node::at(store, &node.right);
+            } else if hi.map_or(false, |hi| node.key >= *hi) {
+                subtree = Node::at(store, &node.left);
+            } else {
+                break;
+            }
+        }
+        subtree
+    }
+
+    /// Returns true if every key in the tree `a` is also in the tree `b`. The keys of `a` are
+    /// partitioned by the root of `b` and checked against its subtrees, so the search stops as
+    /// soon as a part of `a` is found that has no counterpart in `b`.
+    pub fn is_subset(a: (&S, &Link<K, V, S, A>), b: (&S, &Link<K, V, S, A>)) -> bool {
+        let ((sa, a), (sb, b)) = (a, b);
+        // Each item asks whether the keys in `a` strictly between the bounds are all in `b`
+        let mut work = vec![(Node::at(sa, a), None, None, Node::at(sb, b))];
+        while let Some((a, lo, hi, b)) = work.pop() {
+            let a = match Node::restrict(sa, a, lo, hi) {
+                None => continue,
+                Some(a) => a,
+            };
+            let b = match b {
+                None => return false,
+                Some(b) => b,
+            };
+            work.push((Some(a), Some(&b.key), hi, Node::at(sb, &b.right)));
+            work.push((Some(a), lo, Some(&b.key), Node::at(sb, &b.left)));
+        }
+        true
+    }
+
+    /// Returns true if no key in the tree `a` is in the tree `b`.
+    pub fn is_disjoint(a: (&S, &Link<K, V, S, A>), b: (&S, &Link<K, V, S, A>)) -> bool {
+        let ((sa, a), (sb, b)) = (a, b);
+        // Each item asks whether no key in `a` strictly between the bounds is in `b`, where all
+        // the keys in `b` are between the bounds
+        let mut work = vec![(Node::at(sa, a), None, None, Node::at(sb, b))];
+        while let Some((a, lo, hi, b)) = work.pop() {
+            let a = match Node::restrict(sa, a, lo, hi) {
+                None => continue,
+                Some(a) => a,
+            };
+            let b = match b {
+                None => continue,
+                Some(b) => b,
+            };
+            if Node::get(sa, Some(a), &b.key).is_some() {
+                return false;
+            }
+            work.push((Some(a), Some(&b.key), hi, Node::at(sb, &b.right)));
+            work.push((Some(a), lo, Some(&b.key), Node::at(sb, &b.left)));
+        }
+        true
+    }
+
+    /// Assert that the subtree is ordered by key, is a max-heap by priority and has correct
+    /// subtree sizes.
+    #[cfg(test)]
+    pub fn check_invariants(store: &S, subtree: &Link<K, V, S, A>) {
+        let mut stack: Vec<&Node<K, V, S, A>> = Node::at(store, subtree).into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Some(left) = Node::at(store, &node.left) {
+                assert!(left.priority <= node.priority, "heap property violated");
+                assert!(
+                    Node::rightmost(store, left).key < node.key,
+                    "left subtree out of order"
+                );
+                stack.push(left);
+            }
+            if let Some(right) = Node::at(store, &node.right) {
+                assert!(right.priority <= node.priority, "heap property violated");
+                assert!(
+                    Node::leftmost(store, right).key > node.key,
+                    "right subtree out of order"
+                );
+                stack.push(right);
+            }
+            assert_eq!(
+                node.size,
+                1 + Node::size(store, &node.left) + Node::size(store, &node.right)
+            );
+        }
+    }
+
+    /// Assert that the summaries kept in the subtree are those of its entries.
+    #[cfg(test)]
+    pub fn check_summaries(store: &S, subtree: &Link<K, V, S, A>)
+    where
+        A::Summary: PartialEq + ::std::fmt::Debug,
+    {
+        let mut stack: Vec<&Node<K, V, S, A>> = Node::at(store, subtree).into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let (left, right) = (Node::at(store, &node.left), Node::at(store, &node.right));
+            if let Some(ref summary) = node.summary {
+                assert!(
+                    left.map_or(true, |l| l.summary.is_some()),
+                    "summary above a change"
+                );
+                assert!(
+                    right.map_or(true, |r| r.summary.is_some()),
+                    "summary above a change"
+                );
+                let entry = A::summarize(&node.key, &node.value);
+                let expected = A::combine(
+                    &A::combine(&Node::summary(store, &node.left), &entry),
+                    &Node::summary(store, &node.right),
+                );
+                assert_eq!(*summary, expected);
+            }
+            stack.extend(left);
+            stack.extend(right);
+        }
+    }
+}
+
+/// The search path for a key, detached from a tree so that the entry at its end can be read,
+/// changed, removed or inserted without searching again. Each node on the path is missing its
+/// child towards the key. Dropping the path puts it back, which also recomputes the summary of
+/// the node at its end.
+pub struct Path<'a, K: 'a, V: 'a, S: 'a + Store<K, V, A>, A: 'a + Aggregate<K, V>> {
+    // The store and the link the path hangs from, until the path is put back
+    #[allow(clippy::type_complexity)]
+    tree: Option<(&'a mut S, &'a mut Link<K, V, S, A>)>,
+    // The nodes from the root down, each with the direction taken towards the key (true for left)
+    nodes: Vec<(S::Handle, bool)>,
+    // The node with the key and its subtrees, if the key is in the tree
+    end: Link<K, V, S, A>,
+}
+
+impl<'a, K, V, S: Store<K, V, A>, A: Aggregate<K, V>> Path<'a, K, V, S, A> {
+    /// Detach the path from the root of the tree to the node where `step` returns `None`, going
+    /// left where it returns `Some(true)` and right where it returns `Some(false)`.
+    pub fn descend<F>(store: &'a mut S, root: &'a mut Link<K, V, S, A>, mut step: F) -> Self
+    where
+        F: FnMut(&Node<K, V, S, A>) -> Option<bool>,
+    {
+        let mut nodes = Vec::new();
+        let mut cur = root.take();
+        while let Some(mut node) = cur {
+            let left = match step(store.node(&node)) {
+                None => {
+                    cur = Some(node);
+                    break;
+                }
+                Some(left) => left,
+            };
+            let n = store.node_mut(&mut node);
+            cur = if left { n.left.take() } else { n.right.take() };
+            nodes.push((node, left));
+        }
+        Path {
+            tree: Some((store, root)),
+            nodes,
+            end: cur,
+        }
+    }
+
+    /// The node at the end of the path.
+    pub fn end(&self) -> Option<&Node<K, V, S, A>> {
+        let (ref store, _) = *self.tree.as_ref().expect("path is put back once");
+        Node::at(store, &self.end)
+    }
+
+    /// Mutably borrow the node at the end of the path.
+    pub fn end_mut(&mut self) -> Option<&mut Node<K, V, S, A>> {
+        let (ref mut store, _) = *self.tree.as_mut().expect("path is put back once");
+        match self.end {
+            Some(ref mut end) => Some(store.node_mut(end)),
+            None => None,
+        }
+    }
+
+    /// Put the path back and return the node at its end.
+    pub fn into_end_mut(mut self) -> Option<&'a mut Node<K, V, S, A>> {
+        self.end.as_ref()?;
+        let mut directions: Vec<bool> = self.nodes.iter().map(|&(_, left)| left).collect();
+        directions.reverse();
+        let (store, root) = self.put_back();
+        store.descend_mut(root, |_| directions.pop())
+    }
+
+    /// Remove the node at the end of the path, merging its subtrees in its place, and put the
+    /// path back.
+    pub fn remove(mut self) -> Option<Node<K, V, S, A>> {
+        let mut end = self.end.take()?;
+        let store = &mut *self.tree.as_mut().expect("path is put back once").0;
+        let (left, right) = Node::take_children(store, &mut end);
+        self.end = Node::merge(store, left, right);
+        let (store, _) = self.put_back();
+        Some(store.take(end))
+    }
+
+    /// Insert a node at the end of the path, which must not have a node yet. The new node takes
+    /// the place of the first node on the path with a lower priority, and the nodes further down
+    /// the path are split by its key into its left and right subtrees, from the bottom up like
+    /// in `split`. The path then ends at the new node.
+    pub fn insert(&mut self, new: Node<K, V, S, A>) {
+        debug_assert!(self.end.is_none());
+        let store = &mut *self.tree.as_mut().expect("path is put back once").0;
+        let keep = self
+            .nodes
+            .iter()
+            .position(|(node, _)| store.node(node).priority < new.priority)
+            .unwrap_or(self.nodes.len());
+        let below = self.nodes.split_off(keep);
+        let (mut left, mut right) = (None, None);
+        for (mut node, went_left) in below.into_iter().rev() {
+            if went_left {
+                Node::set_left(store, &mut node, right);
+                right = Some(node);
+            } else {
+                Node::set_right(store, &mut node, left);
+                left = Some(node);
+            }
+        }
+        let mut new = store.alloc(new);
+        Node::set_children(store, &mut new, left, right);
+        self.end = Some(new);
+    }
+
+    // Put the nodes back from the bottom up, with the end of the path in place, and return the
+    // store and the root link.
+    fn put_back(&mut self) -> (&'a mut S, &'a mut Link<K, V, S, A>) {
+        let (store, root) = self.tree.take().expect("path is put back once");
+        let mut subtree = self.end.take();
+        if let Some(ref mut end) = subtree {
+            Node::update(store, end);
+        }
+        while let Some((mut node, left)) = self.nodes.pop() {
+            if left {
+                Node::set_left(store, &mut node, subtree);
+            } else {
+                Node::set_right(store, &mut node, subtree);
+            }
+            subtree = Some(node);
+        }
+        *root = subtree;
+        (store, root)
+    }
+}
+
+impl<'a, K: Ord, V, S: Store<K, V, A>, A: Aggregate<K, V>> Path<'a, K, V, S, A> {
+    /// Detach the search path for `key` from the tree at `root`.
+    pub fn search<Q: ?Sized + Ord>(
+        store: &'a mut S,
+        root: &'a mut Link<K, V, S, A>,
+        key: &Q,
+    ) -> Self
+    where
+        K: Borrow<Q>,
+    {
+        Path::descend(store, root, |node| match key.cmp(node.key.borrow()) {
+            Ordering::Equal => None,
+            Ordering::Less => Some(true),
+            Ordering::Greater => Some(false),
+        })
+    }
+}
+
+impl<'a, K, V, S: Store<K, V, A>, A: Aggregate<K, V>> Drop for Path<'a, K, V, S, A> {
+    fn drop(&mut self) {
+        if self.tree.is_some() {
+            self.put_back();
+        }
+    }
+}
+
+/// Takes a tree apart in key order, handing out its nodes detached from their subtrees.
+pub struct Unlink<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> {
+    // Nodes to hand out with their left subtrees removed
+    nodes: Vec<S::Handle>,
+    pub remaining: usize,
+}
+
+impl<K, V, S: Store<K, V, A>, A: Aggregate<K, V>> Unlink<K, V, S, A> {
+    pub fn new(store: &mut S, subtree: Link<K, V, S, A>) -> Unlink<K, V, S, A> {
+        let mut unlink = Unlink {
+            nodes: Vec::new(),
+            remaining: Node::size(store, &subtree),
         };
-        match case {
-            RemovalCases::RemoveNode => subtree.take().map(|n| n.value),
-            RemovalCases::RotateLeft => subtree.as_mut().and_then(|n| {
-                n.left_rotate();
-                Node::rotate_down(&mut n.left)
-            }),
-            RemovalCases::RotateRight => subtree.as_mut().and_then(|n| {
-                n.right_rotate();
-                Node::rotate_down(&mut n.right)
-            }),
+        unlink.push_left_spine(store, subtree);
+        unlink
+    }
+
+    fn push_left_spine(&mut self, store: &mut S, mut subtree: Link<K, V, S, A>) {
+        while let Some(mut node) = subtree {
+            subtree = store.node_mut(&mut node).left.take();
+            self.nodes.push(node);
         }
     }
 
-    #[inline]
-    fn is_heap_property_violated(&self, subtree: &Option<Box<Node<K, V>>>) -> bool {
-        match *subtree {
-            None => false,
-            Some(ref b) => self.priority < b.priority,
-        }
-    }
-
-    //       q               p
-    //      / \             / \
-    //     p  C   --->     A  q
-    //    / \                / \
-    //   A  B               B  C
-    fn right_rotate(&mut self) {
-        // Cut left subtree of q
-        let left = mem::replace(&mut self.left, None);
-        if let Some(mut node) = left {
-            // Let subtree p be root and `node` point to q
-            mem::swap(self, &mut *node);
-            // Move subtree B from p to left subtree of q
-            mem::swap(&mut self.right, &mut node.left);
-            // Let q be right child of p
-            mem::replace(&mut self.right, Some(node));
-        }
-    }
-
-    //     p               q
-    //    / \             / \
-    //   A  q   --->     p  C
-    //     / \          / \
-    //    B  C         A  B
-    fn left_rotate(&mut self) {
-        // Cut right subtree of p
-        let right = mem::replace(&mut self.right, None);
-        if let Some(mut node) = right {
-            // Let subtree q be root and `node` point to p
-            mem::swap(self, &mut *node);
-            // Move subtree B from q to right subtree of p
-            mem::swap(&mut self.left, &mut node.right);
-            // Let p be left child of q
-            mem::replace(&mut self.left, Some(node));
+    /// The next node in key order, detached from its subtrees.
+    pub fn next_node(&mut self, store: &mut S) -> Option<S::Handle> {
+        let mut node = self.nodes.pop()?;
+        let right = store.node_mut(&mut node).right.take();
+        self.push_left_spine(store, right);
+        self.remaining -= 1;
+        Some(node)
+    }
+
+    /// Release the nodes that have not been handed out.
+    pub fn drop_rest(&mut self, store: &mut S) {
+        for node in self.nodes.drain(..) {
+            store.drop_tree(Some(node));
+        }
+        self.remaining = 0;
+    }
+}
+
+/// A mutable iterator over the entries of a tree in a linked store, in pre-order.
+pub struct PreorderMut<'a, K: 'a, V: 'a, S: 'a + Linked<K, V, A>, A: 'a + Aggregate<K, V>> {
+    nodes: Vec<&'a mut Node<K, V, S, A>>,
+    remaining: usize,
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> PreorderMut<'a, K, V, S, A> {
+    pub fn new(subtree: &'a mut Link<K, V, S, A>) -> PreorderMut<'a, K, V, S, A> {
+        let nodes: Vec<_> = subtree.as_mut().map(S::get_mut).into_iter().collect();
+        let remaining = nodes.first().map_or(0, |n| n.size);
+        PreorderMut { nodes, remaining }
+    }
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> Iterator for PreorderMut<'a, K, V, S, A> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        let node = self.nodes.pop()?;
+        node.mark_changed();
+        let Node {
+            ref key,
+            ref mut value,
+            ref mut left,
+            ref mut right,
+            ..
+        } = *node;
+        self.nodes.extend(right.as_mut().map(S::get_mut));
+        self.nodes.extend(left.as_mut().map(S::get_mut));
+        self.remaining -= 1;
+        Some((key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for PreorderMut<'a, K, V, S, A>
+{
+}
+
+/// A mutable iterator over consecutive entries of a tree in a linked store, in key order.
+pub struct InorderMut<'a, K: 'a, V: 'a, S: 'a + Linked<K, V, A>, A: 'a + Aggregate<K, V>> {
+    // Entries to emit, each to be followed by the subtree in the link
+    #[allow(clippy::type_complexity)]
+    nodes: Vec<(&'a K, &'a mut V, &'a mut Link<K, V, S, A>)>,
+    remaining: usize,
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> InorderMut<'a, K, V, S, A> {
+    pub fn new(subtree: &'a mut Link<K, V, S, A>, mut skip: usize, len: usize) -> Self {
+        let mut iter = InorderMut {
+            nodes: Vec::new(),
+            remaining: len,
+        };
+        // Keep the nodes at or after position `skip` on the way down to it
+        let mut cur = subtree;
+        while let Some(node) = cur.as_mut() {
+            let node = S::get_mut(node);
+            node.mark_changed();
+            let Node {
+                ref key,
+                ref mut value,
+                ref mut left,
+                ref mut right,
+                ..
+            } = *node;
+            let before = left.as_ref().map_or(0, |n| S::get(n).size);
+            if skip <= before {
+                iter.nodes.push((key, value, right));
+                cur = left;
+            } else {
+                skip -= before + 1;
+                cur = right;
+            }
+        }
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut cur: &'a mut Link<K, V, S, A>) {
+        while let Some(node) = cur.as_mut() {
+            let node = S::get_mut(node);
+            node.mark_changed();
+            let Node {
+                ref key,
+                ref mut value,
+                ref mut left,
+                ref mut right,
+                ..
+            } = *node;
+            self.nodes.push((key, value, right));
+            cur = left;
+        }
+    }
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> Iterator for InorderMut<'a, K, V, S, A> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let (key, value, right) = self.nodes.pop()?;
+        self.push_left_spine(right);
+        Some((key, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V, S: Linked<K, V, A>, A: Aggregate<K, V>> ExactSizeIterator
+    for InorderMut<'a, K, V, S, A>
+{
+}
+
+/// Walk down a tree in a linked store like `Store::descend_mut`.
+pub fn descend_linked<K, V, S, A, F>(
+    mut cur: &mut Link<K, V, S, A>,
+    mut step: F,
+) -> Option<&mut Node<K, V, S, A>>
+where
+    S: Linked<K, V, A>,
+    A: Aggregate<K, V>,
+    F: FnMut(&Node<K, V, S, A>) -> Option<bool>,
+{
+    loop {
+        let node = S::get_mut(cur.as_mut()?);
+        node.mark_changed();
+        match step(node) {
+            None => return Some(node),
+            Some(true) => cur = &mut node.left,
+            Some(false) => cur = &mut node.right,
         }
     }
 }
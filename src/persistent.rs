@@ -0,0 +1,767 @@
+//! A persistent treap map
+//!
+//! `PersistentTreapMap` never changes once built. Operations like `insert` and `remove` return a
+//! new version of the map that shares all nodes off the modified path with the old version, so
+//! each new version costs O(log n) extra memory and old versions stay valid. Cloning a version
+//! is O(1). A version is a `TreapMap` with `Shared` storage, whose nodes are reference counted
+//! with `Arc` and copied when they are changed while shared, so versions can be shared between
+//! threads.
+//!
+//! `SharedTreapMap` is a mutable `TreapMap` with the same storage. Cloning it is O(1) as well, and
+//! each change copies only the nodes it touches that are still shared with a clone.
+//!
+//! A `Snapshot` is a read-only view of a version or of a `SharedTreapMap`, taken in O(1). Any
+//! other `TreapMap` can take a snapshot too, by copying its entries in O(n). A snapshot of a map
+//! with an `Aggregate` keeps the summaries of its subtrees and can combine them over key ranges
+//! like the map.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::iter::FromIterator;
+use std::ops::RangeBounds;
+
+use rand::prng::XorShiftRng;
+
+use aggregate::{self, Aggregate};
+use arena::Arena;
+use map::{OrderedIter, Range, TreapMap};
+use node::{Link, Node, Store};
+use priority::PrioritySource;
+use storage::{Boxed, Shared};
+
+/// A persistent map based on a randomized treap with structurally shared nodes.
+pub struct PersistentTreapMap<K: Clone, V: Clone, P = XorShiftRng> {
+    map: TreapMap<K, V, P, Shared>,
+}
+
+/// A `TreapMap` whose nodes are shared with its clones and snapshots and copied on write.
+///
+/// ```
+/// let mut t = treap::SharedTreapMap::default();
+/// t.insert(1, "a");
+/// let u = t.clone();
+/// t.insert(1, "b");
+/// assert_eq!(u.get(&1), Some(&"a"));
+/// ```
+pub type SharedTreapMap<K, V, P = XorShiftRng> = TreapMap<K, V, P, Shared>;
+
+/// A read-only point-in-time view of a treap. Snapshots are cheap to clone and can be sent to
+/// and shared between threads when the keys and values can.
+pub struct Snapshot<K: Clone, V: Clone, A: Aggregate<K, V> = ()> {
+    store: Shared,
+    root: Link<K, V, Shared, A>,
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentTreapMap<K, V, XorShiftRng> {
+    /// Create an empty persistent treap with the default random number generator.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new();
+    /// let u = t.insert(5, "yellow");
+    /// assert_eq!(t.get(&5), None);
+    /// assert_eq!(u.get(&5), Some(&"yellow"));
+    /// ```
+    pub fn new() -> PersistentTreapMap<K, V, XorShiftRng> {
+        PersistentTreapMap {
+            map: TreapMap::new_in(Shared),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, P: PrioritySource<K> + Clone> PersistentTreapMap<K, V, P> {
+    /// Create an empty persistent treap that draws the priorities of new keys from the given
+    /// source. Each new version continues from a copy of the source of the version it was made
+    /// from.
+    ///
+    /// ```
+    /// use treap::priority::HashedPriorities;
+    ///
+    /// let t = treap::PersistentTreapMap::new_with_priorities(HashedPriorities::new(1));
+    /// assert!(t.insert("a", 1).contains_key("a"));
+    /// ```
+    pub fn new_with_priorities(priorities: P) -> PersistentTreapMap<K, V, P> {
+        PersistentTreapMap {
+            map: TreapMap::new_with_priorities_in(priorities, Shared),
+        }
+    }
+
+    /// Return the number of elements in the treap.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new();
+    /// assert_eq!(t.len(), 0);
+    /// assert_eq!(t.insert(5, 1).len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Return true if the treap contains no elements.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new();
+    /// assert!(t.is_empty());
+    /// assert!(!t.insert(5, 1).is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Borrow the value corresponding to the given key if it exists in the treap.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new().insert(5, "yellow");
+    /// assert_eq!(t.get(&5), Some(&"yellow"));
+    /// assert_eq!(t.get(&10), None);
+    /// ```
+    pub fn get<Q: ?Sized + Ord>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.map.get(key)
+    }
+
+    /// Borrow the stored key and the value corresponding to the given key if it exists in the
+    /// treap.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new().insert(5, "yellow");
+    /// assert_eq!(t.get_key_value(&5), Some((&5, &"yellow")));
+    /// ```
+    pub fn get_key_value<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.map.get_key_value(key)
+    }
+
+    /// Returns true if the key is present in the treap.
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new().insert(5, "yellow");
+    /// assert!(t.contains_key(&5));
+    /// assert!(!t.contains_key(&8));
+    /// ```
+    pub fn contains_key<Q: ?Sized + Ord>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.map.contains_key(key)
+    }
+
+    /// Return a new version of the treap with the given key mapped to `value`. If the key is
+    /// already present its value is replaced in the new version.
+    ///
+    /// ```
+    /// let v1 = treap::PersistentTreapMap::new().insert(5, "yellow");
+    /// let v2 = v1.insert(5, "blue");
+    /// assert_eq!(v1.get(&5), Some(&"yellow"));
+    /// assert_eq!(v2.get(&5), Some(&"blue"));
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> PersistentTreapMap<K, V, P> {
+        let mut map = self.map.clone();
+        map.insert(key, value);
+        PersistentTreapMap { map }
+    }
+
+    /// Return a new version of the treap without the given key.
+    ///
+    /// ```
+    /// let v1 = treap::PersistentTreapMap::new().insert(5, "yellow");
+    /// let v2 = v1.remove(&5);
+    /// assert!(v1.contains_key(&5));
+    /// assert!(!v2.contains_key(&5));
+    /// ```
+    pub fn remove<Q: ?Sized + Ord>(&self, key: &Q) -> PersistentTreapMap<K, V, P>
+    where
+        K: Borrow<Q>,
+    {
+        let mut map = self.map.clone();
+        map.remove(key);
+        PersistentTreapMap { map }
+    }
+
+    /// Split the treap in two at the given key. Returns a treap with the keys less than `key` and
+    /// a treap with the keys greater than or equal to `key`. `self` is left unchanged.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let (lo, hi) = t.split(&4);
+    /// assert_eq!(lo.len(), 3);
+    /// assert_eq!(hi.len(), 6);
+    /// assert_eq!(t.len(), 9);
+    /// ```
+    pub fn split<Q: ?Sized + Ord>(
+        &self,
+        key: &Q,
+    ) -> (PersistentTreapMap<K, V, P>, PersistentTreapMap<K, V, P>)
+    where
+        K: Borrow<Q>,
+    {
+        let mut left = self.map.clone();
+        let right = left.split_off(key);
+        (
+            PersistentTreapMap { map: left },
+            PersistentTreapMap { map: right },
+        )
+    }
+
+    /// Join two treaps where every key in `left` is less than every key in `right` into a new
+    /// treap. The priority source of `left` is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the largest key in `left` is not less than the smallest key in `right`.
+    ///
+    /// ```
+    /// let left: treap::PersistentTreapMap<_, _> = (0..5).map(|k| (k, k)).collect();
+    /// let right: treap::PersistentTreapMap<_, _> = (5..10).map(|k| (k, k)).collect();
+    /// let t = treap::PersistentTreapMap::join(&left, &right);
+    /// assert_eq!(t.len(), 10);
+    /// assert_eq!(left.len(), 5);
+    /// ```
+    pub fn join(
+        left: &PersistentTreapMap<K, V, P>,
+        right: &PersistentTreapMap<K, V, P>,
+    ) -> PersistentTreapMap<K, V, P> {
+        PersistentTreapMap {
+            map: TreapMap::join(left.map.clone(), right.map.clone()),
+        }
+    }
+
+    /// Borrow the entry with the smallest key in the treap.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = vec![(5, "a"), (2, "b")].into_iter().collect();
+    /// assert_eq!(t.first_key_value(), Some((&2, &"b")));
+    /// ```
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.map.first_key_value()
+    }
+
+    /// Borrow the entry with the largest key in the treap.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = vec![(5, "a"), (2, "b")].into_iter().collect();
+    /// assert_eq!(t.last_key_value(), Some((&5, &"a")));
+    /// ```
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.map.last_key_value()
+    }
+
+    /// Returns an iterator over keys and values in the treap that gives the keys in sorted order.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = (1..5).rev().map(|k| (k, ())).collect();
+    /// let v: Vec<_> = t.iter_ordered().map(|(k, _)| *k).collect();
+    /// assert_eq!(v, vec![1, 2, 3, 4]);
+    /// ```
+    pub fn iter_ordered(&self) -> OrderedIter<'_, K, V, Shared> {
+        self.map.iter_ordered()
+    }
+
+    /// Returns an iterator over the entries with keys in the given range, in key order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let v: Vec<_> = t.range(3..6).map(|(k, _)| *k).collect();
+    /// assert_eq!(v, vec![3, 4, 5]);
+    /// ```
+    pub fn range<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> Range<'_, K, V, Shared>
+    where
+        K: Borrow<Q>,
+    {
+        self.map.range(range)
+    }
+
+    /// Take a read-only snapshot of this version in O(1).
+    ///
+    /// ```
+    /// let t = treap::PersistentTreapMap::new().insert(1, "a");
+    /// let snapshot = t.snapshot();
+    /// let t = t.insert(2, "b");
+    /// assert_eq!(snapshot.len(), 1);
+    /// assert_eq!(t.len(), 2);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        self.map.snapshot()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, P: PrioritySource<K>, A: Aggregate<K, V>>
+    TreapMap<K, V, P, Shared, A>
+{
+    /// Take a read-only snapshot of the treap in O(1). The snapshot shares the nodes of the treap,
+    /// and later changes to the treap copy the nodes they touch instead of changing them.
+    ///
+    /// ```
+    /// let mut t: treap::SharedTreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let snapshot = t.snapshot();
+    /// t.clear();
+    /// assert_eq!(snapshot.get(&3), Some(&30));
+    /// assert_eq!(snapshot.len(), 9);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<K, V, A> {
+        Snapshot {
+            store: Shared,
+            root: self.tree().1.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, P: PrioritySource<K>, A: Aggregate<K, V>>
+    TreapMap<K, V, P, Boxed, A>
+{
+    /// Take a read-only snapshot of the treap that later changes to it do not affect. The nodes
+    /// of a boxed treap cannot be shared, so this copies every entry in O(n); a `SharedTreapMap`
+    /// takes snapshots in O(1).
+    ///
+    /// ```
+    /// let mut t: treap::TreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let snapshot = t.snapshot();
+    /// t.clear();
+    /// assert_eq!(snapshot.get(&3), Some(&30));
+    /// assert_eq!(snapshot.len(), 9);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<K, V, A> {
+        let (store, root) = self.tree();
+        Snapshot::copy_of(store, root)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, P: PrioritySource<K>, A: Aggregate<K, V>>
+    TreapMap<K, V, P, Arena<K, V, A>, A>
+{
+    /// Take a read-only snapshot of the treap by copying its entries in O(n), like the snapshot
+    /// of a boxed treap.
+    ///
+    /// ```
+    /// let mut t: treap::ArenaTreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let snapshot = t.snapshot();
+    /// t.insert(3, 0);
+    /// assert_eq!(snapshot.get(&3), Some(&30));
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<K, V, A> {
+        let (store, root) = self.tree();
+        Snapshot::copy_of(store, root)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: Aggregate<K, V>> Snapshot<K, V, A> {
+    // Copy a tree from another store into shared nodes in key order. The copies keep their
+    // priorities, so the snapshot has the shape of the tree.
+    fn copy_of<S: Store<K, V, A>>(store: &S, root: &Link<K, V, S, A>) -> Snapshot<K, V, A> {
+        let mut shared = Shared;
+        let mut nodes = Vec::with_capacity(Node::size(store, root));
+        let mut stack = Vec::new();
+        let mut cur = Node::at(store, root);
+        loop {
+            while let Some(node) = cur {
+                stack.push(node);
+                cur = Node::at(store, &node.left);
+            }
+            let node = match stack.pop() {
+                Some(node) => node,
+                None => break,
+            };
+            nodes.push(shared.alloc(node.copy_detached()));
+            cur = Node::at(store, &node.right);
+        }
+        let root =
+            Node::from_sorted_nodes(&mut shared, nodes).expect("the keys of a treap are sorted");
+        Snapshot {
+            store: shared,
+            root,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: Aggregate<K, V>> Snapshot<K, V, A> {
+    /// Return the number of elements in the snapshot.
+    pub fn len(&self) -> usize {
+        Node::size(&self.store, &self.root)
+    }
+
+    /// Return true if the snapshot contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Borrow the value corresponding to the given key if it exists in the snapshot.
+    ///
+    /// ```
+    /// let mut t = treap::SharedTreapMap::default();
+    /// t.insert(5, "yellow");
+    /// let snapshot = t.snapshot();
+    /// t.insert(5, "blue");
+    /// assert_eq!(snapshot.get(&5), Some(&"yellow"));
+    /// ```
+    pub fn get<Q: ?Sized + Ord>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    /// Borrow the stored key and the value corresponding to the given key if it exists in the
+    /// snapshot.
+    pub fn get_key_value<Q: ?Sized + Ord>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+    {
+        let root = Node::at(&self.store, &self.root);
+        Node::get(&self.store, root, key).map(|n| (&n.key, &n.value))
+    }
+
+    /// Returns true if the key is present in the snapshot.
+    pub fn contains_key<Q: ?Sized + Ord>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.get_key_value(key).is_some()
+    }
+
+    /// Returns an iterator over keys and values in the snapshot that gives the keys in sorted
+    /// order.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = (1..5).rev().map(|k| (k, ())).collect();
+    /// let v: Vec<_> = t.snapshot().iter_ordered().map(|(k, _)| *k).collect();
+    /// assert_eq!(v, vec![1, 2, 3, 4]);
+    /// ```
+    pub fn iter_ordered(&self) -> OrderedIter<'_, K, V, Shared, A> {
+        OrderedIter::new(&self.store, &self.root)
+    }
+
+    /// Returns an iterator over the entries with keys in the given range, in key order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let t: treap::PersistentTreapMap<_, _> = (1..10).map(|k| (k, k * 10)).collect();
+    /// let v: Vec<_> = t.snapshot().range(3..6).map(|(k, _)| *k).collect();
+    /// assert_eq!(v, vec![3, 4, 5]);
+    /// ```
+    pub fn range<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> Range<'_, K, V, Shared, A>
+    where
+        K: Borrow<Q>,
+    {
+        Range::new(&self.store, &self.root, range)
+    }
+
+    /// Combine the summaries of the entries with keys in the given range, like
+    /// `TreapMap::aggregate`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// use treap::aggregate::Sum;
+    /// use treap::storage::Shared;
+    ///
+    /// let mut t: treap::TreapMap<_, _, _, Shared, Sum> = (1..10).map(|k| (k, k)).collect();
+    /// let snapshot = t.snapshot();
+    /// t.insert(5, 500);
+    /// assert_eq!(snapshot.aggregate(4..7), 15);
+    /// assert_eq!(t.aggregate(4..7), 510);
+    /// ```
+    pub fn aggregate<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> A::Summary
+    where
+        K: Borrow<Q>,
+    {
+        aggregate::range_summary(&self.store, &self.root, range)
+    }
+}
+
+impl<K: Clone, V: Clone, A: Aggregate<K, V>> Clone for Snapshot<K, V, A> {
+    fn clone(&self) -> Snapshot<K, V, A> {
+        Snapshot {
+            store: Shared,
+            root: self.root.clone(),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, A: Aggregate<K, V>> Drop for Snapshot<K, V, A> {
+    fn drop(&mut self) {
+        self.store.drop_tree(self.root.take());
+    }
+}
+
+impl<K, V, A> fmt::Debug for Snapshot<K, V, A>
+where
+    K: Ord + Clone + fmt::Debug,
+    V: Clone + fmt::Debug,
+    A: Aggregate<K, V>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter_ordered()).finish()
+    }
+}
+
+impl<'a, K: Ord + Clone, V: Clone, A: Aggregate<K, V>> IntoIterator for &'a Snapshot<K, V, A> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = OrderedIter<'a, K, V, Shared, A>;
+
+    fn into_iter(self) -> OrderedIter<'a, K, V, Shared, A> {
+        self.iter_ordered()
+    }
+}
+
+/// Cloning a persistent treap only copies a pointer to its root and its priority source.
+impl<K: Clone, V: Clone, P: Clone> Clone for PersistentTreapMap<K, V, P> {
+    fn clone(&self) -> PersistentTreapMap<K, V, P> {
+        PersistentTreapMap {
+            map: self.map.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> FromIterator<(K, V)> for PersistentTreapMap<K, V> {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> PersistentTreapMap<K, V> {
+        PersistentTreapMap {
+            map: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Default for PersistentTreapMap<K, V> {
+    fn default() -> PersistentTreapMap<K, V> {
+        PersistentTreapMap::new()
+    }
+}
+
+/// Treaps are compared by their entries in key order.
+impl<K: Ord + Clone, V: PartialEq + Clone, P: PrioritySource<K> + Clone> PartialEq
+    for PersistentTreapMap<K, V, P>
+{
+    fn eq(&self, other: &PersistentTreapMap<K, V, P>) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<K: Ord + Clone, V: Eq + Clone, P: PrioritySource<K> + Clone> Eq
+    for PersistentTreapMap<K, V, P>
+{
+}
+
+impl<K, V, P> fmt::Debug for PersistentTreapMap<K, V, P>
+where
+    K: Ord + Clone + fmt::Debug,
+    V: Clone + fmt::Debug,
+    P: PrioritySource<K> + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<'a, K: Ord + Clone, V: Clone, P: PrioritySource<K> + Clone> IntoIterator
+    for &'a PersistentTreapMap<K, V, P>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = OrderedIter<'a, K, V, Shared>;
+
+    fn into_iter(self) -> OrderedIter<'a, K, V, Shared> {
+        self.iter_ordered()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PersistentTreapMap, SharedTreapMap};
+    use aggregate::{Max, Sum};
+    use arena::ArenaTreapMap;
+    use map::TreapMap;
+    use node::Node;
+    use priority;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::Arc;
+    use storage::{Boxed, Shared};
+
+    #[test]
+    fn versions_are_independent() {
+        let mut rng = XorShiftRng::from_seed([3; 16]);
+        let mut versions = vec![(PersistentTreapMap::new(), BTreeMap::new())];
+        for _ in 0..2000 {
+            let (t, m) = versions[rng.gen_range(0, versions.len())].clone();
+            let k = rng.gen_range(0, 200);
+            let (t, mut m) = if rng.gen() {
+                (t.insert(k, k * 3), m)
+            } else {
+                (t.remove(&k), m)
+            };
+            if t.contains_key(&k) {
+                m.insert(k, k * 3);
+            } else {
+                m.remove(&k);
+            }
+            versions.push((t, m));
+        }
+        for (t, m) in &versions {
+            t.map.check_invariants();
+            assert_eq!(t.len(), m.len());
+            assert!(t.iter_ordered().eq(m.iter()));
+        }
+    }
+
+    #[test]
+    fn split_and_join_share_nodes() {
+        let t: PersistentTreapMap<_, _> = (0..1000).map(|k| (k, k)).collect();
+        let (lo, hi) = t.split(&500);
+        lo.map.check_invariants();
+        hi.map.check_invariants();
+        assert_eq!((lo.len(), hi.len()), (500, 500));
+        let u = PersistentTreapMap::join(&lo, &hi);
+        u.map.check_invariants();
+        assert_eq!(u, t);
+
+        // A new version only adds the nodes on one path
+        let v = t.insert(1000, 1000).remove(&0);
+        let mut nodes = HashSet::new();
+        let mut stack = vec![t.map.tree().1, v.map.tree().1];
+        while let Some(Some(node)) = stack.pop() {
+            if nodes.insert(&**node as *const _) {
+                stack.push(&node.left);
+                stack.push(&node.right);
+            }
+        }
+        assert!(nodes.len() < 1100);
+    }
+
+    #[test]
+    fn shared_storage_copies_on_write() {
+        let mut a: TreapMap<_, _, _, Shared> = (0..100).map(|k| (k, k)).collect();
+        let mut b = a.clone();
+        *b.get_mut(&10).unwrap() += 1000;
+        for (_, v) in b.range_mut(20..30) {
+            *v += 1000;
+        }
+        b.insert(100, 100);
+        let c = b.clone();
+        for v in b.values_mut() {
+            *v += 1;
+        }
+        a.remove(&50);
+        a.check_invariants();
+        b.check_invariants();
+        assert!(a.iter_ordered().all(|(k, v)| k == v));
+        assert_eq!(a.len(), 99);
+        assert_eq!((b[&10], b[&25], b[&50], b[&100]), (1011, 1026, 51, 101));
+        assert_eq!((c[&10], c[&25], c[&50], c[&100]), (1010, 1025, 50, 100));
+    }
+
+    #[test]
+    fn drop_degenerate_tree() {
+        let mut t = PersistentTreapMap::new_with_priorities(priority::from_fn(|k: &u64| *k));
+        for k in 0..200_000 {
+            t = t.insert(k, ());
+        }
+        let older = t.remove(&0);
+        drop(t);
+        assert_eq!(older.len(), 199_999);
+    }
+
+    #[test]
+    fn snapshots() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut t = PersistentTreapMap::new();
+        let mut snapshots = Vec::new();
+        for k in 0..100 {
+            t = t.insert(k, k);
+            snapshots.push(t.snapshot());
+        }
+        for (i, s) in snapshots.iter().enumerate() {
+            Node::check_invariants(&Shared, &s.root);
+            assert_send_sync(s);
+            assert_eq!(s.len(), i + 1);
+            assert_eq!(s.range(..=i).len(), i + 1);
+            assert!(s.iter_ordered().map(|(k, _)| *k).eq(0..=i));
+        }
+
+        let mut m: SharedTreapMap<_, _> = (0..1000).map(|k| (k, k)).collect();
+        let s = m.snapshot();
+        assert_send_sync(&s);
+        assert!(Arc::ptr_eq(
+            s.root.as_ref().unwrap(),
+            m.tree().1.as_ref().unwrap()
+        ));
+
+        // Changes after the snapshot only copy the nodes on their paths
+        m.insert(1000, 1000);
+        *m.get_mut(&500).unwrap() = 0;
+        let mut nodes = HashSet::new();
+        let mut stack = vec![&s.root, m.tree().1];
+        while let Some(Some(node)) = stack.pop() {
+            if nodes.insert(&**node as *const _) {
+                stack.push(&node.left);
+                stack.push(&node.right);
+            }
+        }
+        assert!(nodes.len() < 1100);
+
+        m.clear();
+        Node::check_invariants(&Shared, &s.root);
+        assert_eq!(s.len(), 1000);
+        assert_eq!(s.get(&500), Some(&500));
+        assert!(s.range(10..20).map(|(k, _)| *k).eq(10..20));
+    }
+
+    #[test]
+    fn snapshots_of_boxed_and_arena_maps() {
+        let mut t: TreapMap<_, _, _, Boxed, Sum> = (0..1000).map(|k| (k, k)).collect();
+        let s = t.snapshot();
+        Node::check_invariants(&Shared, &s.root);
+        // The copy has the shape of the treap
+        assert_eq!(
+            s.root.as_ref().map(|n| n.key),
+            t.tree().1.as_ref().map(|n| n.key)
+        );
+        for v in t.values_mut() {
+            *v = 0;
+        }
+        t.remove(&500);
+        assert_eq!(s.len(), 1000);
+        assert_eq!(s.aggregate(..), 499_500);
+        assert_eq!(s.get(&500), Some(&500));
+        assert_eq!(t.aggregate(..), 0);
+
+        let mut a: ArenaTreapMap<_, _> = (0..100).map(|k| (k, k)).collect();
+        let s = a.snapshot();
+        a.clear();
+        Node::check_invariants(&Shared, &s.root);
+        assert!(s
+            .iter_ordered()
+            .map(|(k, v)| (*k, *v))
+            .eq((0..100).map(|k| (k, k))));
+    }
+
+    #[test]
+    fn snapshots_keep_aggregates() {
+        let mut t: TreapMap<_, _, _, Shared, (Sum, Max)> = (0..100).map(|k| (k, k)).collect();
+        let before = t.snapshot();
+        for (_, v) in t.range_mut(40..60) {
+            *v += 1000;
+        }
+        t.remove(&10);
+        let after = t.snapshot();
+        t.clear();
+        assert_eq!(before.aggregate(..), (4950, Some(99)));
+        assert_eq!(before.aggregate(40..=40), (40, Some(40)));
+        assert_eq!(after.aggregate(..), (4940 + 20_000, Some(1059)));
+        assert_eq!(after.aggregate(5..15), (85, Some(14)));
+        assert!(before.iter_ordered().eq(before.range(..)));
+    }
+}
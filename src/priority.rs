@@ -0,0 +1,223 @@
+//! Sources of node priorities
+//!
+//! A treap keeps its nodes in a max-heap with respect to their priorities. The shape of the tree,
+//! and thereby its expected depth, depends entirely on how those priorities are chosen. A
+//! `TreapMap` is generic over a `PrioritySource` so the priorities can be drawn from a random
+//! number generator (the default), derived from a hash of the key, or given explicitly.
+
+use std::hash::{Hash, Hasher};
+
+use rand;
+
+/// A source of priorities for newly inserted keys.
+///
+/// Any random number generator is a priority source that ignores the key. For the treap to have
+/// an expected depth of O(log n) the priorities should look independent and uniformly
+/// distributed.
+pub trait PrioritySource<K> {
+    /// Return the priority of a node with the given key that is about to be inserted.
+    fn priority(&mut self, key: &K) -> u64;
+}
+
+impl<K, R: rand::Rng> PrioritySource<K> for R {
+    #[inline]
+    fn priority(&mut self, _key: &K) -> u64 {
+        self.gen()
+    }
+}
+
+/// A priority source that computes priorities with a function. Created by `from_fn`.
+#[derive(Debug, Clone, Copy)]
+pub struct FromFn<F> {
+    f: F,
+}
+
+/// Create a priority source from a function that gives the priority of a key.
+///
+/// ```
+/// use treap::priority;
+///
+/// let mut t = treap::TreapMap::new_with_priorities(priority::from_fn(|k: &u64| !k));
+/// t.extend((0..10).map(|k| (k, ())));
+/// assert_eq!(t.len(), 10);
+/// ```
+pub fn from_fn<K, F: FnMut(&K) -> u64>(f: F) -> FromFn<F> {
+    FromFn { f }
+}
+
+impl<K, F: FnMut(&K) -> u64> PrioritySource<K> for FromFn<F> {
+    #[inline]
+    fn priority(&mut self, key: &K) -> u64 {
+        (self.f)(key)
+    }
+}
+
+/// A priority source that derives the priority of a key from a keyed hash of the key.
+///
+/// The shape of a treap is determined by its keys and their priorities, so with hashed priorities
+/// the same set of keys always gives the same tree no matter in which order the keys were
+/// inserted or removed. This makes the treap strongly history-independent. The shape does depend
+/// on the seed, which should be kept secret if the shape must not reveal anything about the keys.
+///
+/// Keys are hashed with SipHash-2-4 using the 128-bit seed as the key. Priorities are
+/// deterministic for a given seed: the same seed and key give the same priority in every run, on
+/// every platform and with every Rust release, as long as the `Hash` implementation of the key
+/// type feeds the hasher the same data.
+///
+/// ```
+/// use treap::priority::{HashedPriorities, PrioritySource};
+///
+/// let mut a = HashedPriorities::new(42);
+/// let mut b = HashedPriorities::new(42);
+/// assert_eq!(a.priority(&"key"), b.priority(&"key"));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashedPriorities {
+    k0: u64,
+    k1: u64,
+}
+
+impl HashedPriorities {
+    /// Create a priority source that hashes keys with the given 128-bit seed as the SipHash key.
+    pub fn new(seed: u128) -> HashedPriorities {
+        HashedPriorities {
+            k0: seed as u64,
+            k1: (seed >> 64) as u64,
+        }
+    }
+}
+
+impl<K: Hash> PrioritySource<K> for HashedPriorities {
+    #[inline]
+    fn priority(&mut self, key: &K) -> u64 {
+        let mut hasher = SipHasher24::new(self.k0, self.k1);
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+// SipHash-2-4. Integers are hashed as little-endian bytes and `usize` as a `u64` so the result is
+// the same on every platform.
+#[derive(Clone)]
+struct SipHasher24 {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl SipHasher24 {
+    fn new(k0: u64, k1: u64) -> SipHasher24 {
+        SipHasher24 {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    #[inline]
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    #[inline]
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.round();
+        self.v0 ^= m;
+    }
+}
+
+impl Hasher for SipHasher24 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.length += bytes.len();
+        for &byte in bytes {
+            self.tail |= u64::from(byte) << (8 * self.ntail);
+            self.ntail += 1;
+            if self.ntail == 8 {
+                let m = self.tail;
+                self.compress(m);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let b = ((self.length as u64 & 0xff) << 56) | self.tail;
+        state.compress(b);
+        state.v2 ^= 0xff;
+        for _ in 0..4 {
+            state.round();
+        }
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SipHasher24;
+    use std::hash::Hasher;
+
+    #[test]
+    fn siphash_reference_vectors() {
+        // Test vectors from the SipHash paper: key 00..0f, messages 00, 00 01, ...
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+        let expected = [
+            0x726f_db47_dd0e_0e31,
+            0x74f8_39c5_93dc_67fd,
+            0x0d6c_8009_d9a9_4f5a,
+            0x8567_6696_d7fb_7e2d,
+        ];
+        for (len, &hash) in expected.iter().enumerate() {
+            let message: Vec<u8> = (0..len as u8).collect();
+            let mut hasher = SipHasher24::new(k0, k1);
+            hasher.write(&message);
+            assert_eq!(hasher.finish(), hash);
+        }
+        let message: Vec<u8> = (0..15).collect();
+        let mut hasher = SipHasher24::new(k0, k1);
+        hasher.write(&message[..5]);
+        hasher.write(&message[5..]);
+        assert_eq!(hasher.finish(), 0xa129_ca61_49be_45e5);
+    }
+}
@@ -1,10 +1,72 @@
-use map::TreapMap;
+use std::borrow::Borrow;
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::{FromIterator, FusedIterator, Peekable};
+use std::ops::{BitAnd, BitOr, BitXor, RangeBounds, Sub};
+
+use rand::prng::XorShiftRng;
+
+use map::{self, TreapMap, UnsortedError};
+use node::Node;
 
 /// A set based on a randomized treap
+#[derive(Clone)]
 pub struct TreapSet<T> {
     map: TreapMap<T, ()>,
 }
 
+/// An iterator over a set's items. The order is arbitrary.
+pub struct Iter<'a, T: 'a> {
+    iter: map::Iter<'a, T, ()>,
+}
+
+/// An iterator over a set's items in sorted order.
+pub struct OrderedIter<'a, T: 'a> {
+    iter: map::OrderedIter<'a, T, ()>,
+}
+
+/// An owning iterator over a set's items. The order is arbitrary.
+pub struct IntoIter<T> {
+    iter: map::IntoIter<T, ()>,
+}
+
+/// A lazy iterator over the items in the union of two sets, in sorted order.
+pub struct Union<'a, T: 'a> {
+    a: Peekable<OrderedIter<'a, T>>,
+    b: Peekable<OrderedIter<'a, T>>,
+}
+
+/// A lazy iterator over the items in the intersection of two sets, in sorted order.
+pub struct Intersection<'a, T: 'a> {
+    a: Peekable<OrderedIter<'a, T>>,
+    b: Peekable<OrderedIter<'a, T>>,
+}
+
+/// A lazy iterator over the items in the difference of two sets, in sorted order.
+pub struct Difference<'a, T: 'a> {
+    a: Peekable<OrderedIter<'a, T>>,
+    b: Peekable<OrderedIter<'a, T>>,
+}
+
+/// A lazy iterator over the items in the symmetric difference of two sets, in sorted order.
+pub struct SymmetricDifference<'a, T: 'a> {
+    a: Peekable<OrderedIter<'a, T>>,
+    b: Peekable<OrderedIter<'a, T>>,
+}
+
+/// An iterator over a sub-range of a set's items in sorted order.
+pub struct Range<'a, T: 'a> {
+    iter: map::Range<'a, T, ()>,
+}
+
+/// An iterator that removes and yields the items of a set for which a predicate holds, in sorted
+/// order. Created by `extract_if`.
+pub struct ExtractIf<'a, T: 'a + Ord, F: FnMut(&T) -> bool> {
+    extraction: map::Extraction<'a, T, (), XorShiftRng>,
+    pred: F,
+}
+
 impl<T: Ord> TreapSet<T> {
     /// Returns a new empty set.
     ///
@@ -19,6 +81,52 @@ impl<T: Ord> TreapSet<T> {
         }
     }
 
+    /// Build a set in linear time from sorted items without duplicates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the items are not strictly increasing. Use `try_from_sorted_iter` to handle such
+    /// input without panicking.
+    ///
+    /// ```
+    /// let s = treap::TreapSet::from_sorted_iter(0..100);
+    /// assert_eq!(s.len(), 100);
+    /// assert!(s.contains(&42));
+    /// ```
+    pub fn from_sorted_iter<I: IntoIterator<Item = T>>(iter: I) -> TreapSet<T> {
+        TreapSet {
+            map: TreapMap::from_sorted_iter(iter.into_iter().map(|item| (item, ()))),
+        }
+    }
+
+    /// Build a set in linear time from a vector of sorted items without duplicates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the items are not strictly increasing.
+    ///
+    /// ```
+    /// let s = treap::TreapSet::from_sorted_vec(vec!["a", "b", "c"]);
+    /// assert!(s.contains("b"));
+    /// ```
+    pub fn from_sorted_vec(items: Vec<T>) -> TreapSet<T> {
+        TreapSet::from_sorted_iter(items)
+    }
+
+    /// Build a set in linear time from sorted items without duplicates, or return an error if the
+    /// items are not strictly increasing.
+    ///
+    /// ```
+    /// assert!(treap::TreapSet::try_from_sorted_iter(vec![1, 2, 3]).is_ok());
+    /// assert!(treap::TreapSet::try_from_sorted_iter(vec![1, 1, 2]).is_err());
+    /// ```
+    pub fn try_from_sorted_iter<I: IntoIterator<Item = T>>(
+        iter: I,
+    ) -> Result<TreapSet<T>, UnsortedError> {
+        let map = TreapMap::try_from_sorted_iter(iter.into_iter().map(|item| (item, ())))?;
+        Ok(TreapSet { map })
+    }
+
     /// Returns the number of elements in the set.
     pub fn len(&self) -> usize {
         self.map.len()
@@ -35,7 +143,17 @@ impl<T: Ord> TreapSet<T> {
     }
 
     /// Returns true if the item is in the set.
-    pub fn contains(&self, item: &T) -> bool {
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// s.insert("apple".to_string());
+    /// assert!(s.contains("apple"));
+    /// assert!(!s.contains("pear"));
+    /// ```
+    pub fn contains<Q: ?Sized + Ord>(&self, item: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
         self.map.get(item).is_some()
     }
 
@@ -44,10 +162,580 @@ impl<T: Ord> TreapSet<T> {
         self.map.insert(item, ()).is_none()
     }
 
+    /// Add a item to the set, replacing an equal item if there is one. Returns the replaced item.
+    /// The item is searched for once, and replacing an item does not change the shape of the
+    /// treap.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// assert_eq!(s.replace(5), None);
+    /// assert_eq!(s.replace(5), Some(5));
+    /// assert_eq!(s.len(), 1);
+    /// ```
+    pub fn replace(&mut self, item: T) -> Option<T> {
+        self.map.replace_key(item)
+    }
+
+    /// Borrow the item in the set that is equal to the given one, if any.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// s.insert("apple".to_string());
+    /// assert_eq!(s.get("apple"), Some(&"apple".to_string()));
+    /// assert_eq!(s.get("pear"), None);
+    /// ```
+    pub fn get<Q: ?Sized + Ord>(&self, item: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.get_key_value(item).map(|(k, _)| k)
+    }
+
+    /// Remove the item in the set that is equal to the given one and return it, if any.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// s.insert(5);
+    /// assert_eq!(s.take(&5), Some(5));
+    /// assert_eq!(s.take(&5), None);
+    /// ```
+    pub fn take<Q: ?Sized + Ord>(&mut self, item: &Q) -> Option<T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.remove_entry(item).map(|(k, _)| k)
+    }
+
     /// Remove a item from the set. Returns true if the item was in the set.
-    pub fn remove(&mut self, item: &T) -> bool {
+    pub fn remove<Q: ?Sized + Ord>(&mut self, item: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
         self.map.remove(item).is_some()
     }
+
+    /// Returns the number of items in the set that are less than the given item.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// s.insert(3);
+    /// s.insert(7);
+    /// assert_eq!(s.rank(&7), 1);
+    /// ```
+    pub fn rank<Q: ?Sized + Ord>(&self, item: &Q) -> usize
+    where
+        T: Borrow<Q>,
+    {
+        self.map.rank(item)
+    }
+
+    /// Returns the item at the given position in sorted order, counting from zero.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// s.insert(3);
+    /// s.insert(7);
+    /// assert_eq!(s.select(1), Some(&7));
+    /// ```
+    pub fn select(&self, index: usize) -> Option<&T> {
+        self.map.select(index).map(|(k, _)| k)
+    }
+
+    /// Returns the item at the given position in sorted order, counting from zero. This is
+    /// another name for `select`.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![3, 7, 5].into_iter().collect();
+    /// assert_eq!(s.nth_ordered(1), Some(&5));
+    /// ```
+    pub fn nth_ordered(&self, index: usize) -> Option<&T> {
+        self.select(index)
+    }
+
+    /// Returns the smallest item in the set.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(s.first(), Some(&1));
+    /// ```
+    pub fn first(&self) -> Option<&T> {
+        self.map.first_key_value().map(|(k, _)| k)
+    }
+
+    /// Returns the largest item in the set.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(s.last(), Some(&3));
+    /// ```
+    pub fn last(&self) -> Option<&T> {
+        self.map.last_key_value().map(|(k, _)| k)
+    }
+
+    /// Returns the greatest item less than or equal to the given item.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![10, 20, 30].into_iter().collect();
+    /// assert_eq!(s.floor(&25), Some(&20));
+    /// ```
+    pub fn floor<Q: ?Sized + Ord>(&self, item: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.floor(item).map(|(k, _)| k)
+    }
+
+    /// Returns the least item greater than or equal to the given item.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![10, 20, 30].into_iter().collect();
+    /// assert_eq!(s.ceiling(&25), Some(&30));
+    /// ```
+    pub fn ceiling<Q: ?Sized + Ord>(&self, item: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.ceiling(item).map(|(k, _)| k)
+    }
+
+    /// Returns the greatest item strictly less than the given item.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![10, 20, 30].into_iter().collect();
+    /// assert_eq!(s.lower(&20), Some(&10));
+    /// ```
+    pub fn lower<Q: ?Sized + Ord>(&self, item: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.lower(item).map(|(k, _)| k)
+    }
+
+    /// Returns the least item strictly greater than the given item.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![10, 20, 30].into_iter().collect();
+    /// assert_eq!(s.higher(&20), Some(&30));
+    /// ```
+    pub fn higher<Q: ?Sized + Ord>(&self, item: &Q) -> Option<&T>
+    where
+        T: Borrow<Q>,
+    {
+        self.map.higher(item).map(|(k, _)| k)
+    }
+
+    /// Remove and return the smallest item in the set.
+    ///
+    /// ```
+    /// let mut s: treap::TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(s.pop_first(), Some(1));
+    /// assert_eq!(s.len(), 2);
+    /// ```
+    pub fn pop_first(&mut self) -> Option<T> {
+        self.map.pop_first().map(|(k, _)| k)
+    }
+
+    /// Remove and return the largest item in the set.
+    ///
+    /// ```
+    /// let mut s: treap::TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(s.pop_last(), Some(3));
+    /// assert_eq!(s.len(), 2);
+    /// ```
+    pub fn pop_last(&mut self) -> Option<T> {
+        self.map.pop_last().map(|(k, _)| k)
+    }
+
+    /// Returns an iterator over the items in the given range, in sorted order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than the range end or if the range start and end are
+    /// equal and both excluded.
+    ///
+    /// ```
+    /// let mut s = treap::TreapSet::new();
+    /// for i in 0..10 {
+    ///     s.insert(i);
+    /// }
+    /// let v: Vec<_> = s.range(4..7).cloned().collect();
+    /// assert_eq!(v, vec![4, 5, 6]);
+    /// ```
+    pub fn range<Q: ?Sized + Ord, R: RangeBounds<Q>>(&self, range: R) -> Range<'_, T>
+    where
+        T: Borrow<Q>,
+    {
+        Range {
+            iter: self.map.range(range),
+        }
+    }
+
+    /// Keep only the items for which `f` returns true.
+    ///
+    /// ```
+    /// let mut s: treap::TreapSet<_> = (0..10).collect();
+    /// s.retain(|&x| x % 2 == 0);
+    /// assert_eq!(s.len(), 5);
+    /// assert!(s.contains(&4));
+    /// assert!(!s.contains(&5));
+    /// ```
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.map.retain(|item, _| f(item));
+    }
+
+    /// Return an iterator that removes the items for which `pred` returns `true` and yields them
+    /// in sorted order. If the iterator is dropped before it is exhausted the remaining items are
+    /// kept.
+    ///
+    /// ```
+    /// let mut s: treap::TreapSet<_> = (0..10).collect();
+    /// let odd: Vec<_> = s.extract_if(|x| x % 2 == 1).collect();
+    /// assert_eq!(odd, vec![1, 3, 5, 7, 9]);
+    /// assert_eq!(s.len(), 5);
+    /// ```
+    pub fn extract_if<F: FnMut(&T) -> bool>(&mut self, pred: F) -> ExtractIf<'_, T, F> {
+        ExtractIf {
+            extraction: map::Extraction::new(&mut self.map),
+            pred,
+        }
+    }
+
+    /// Returns an iterator over the items in the set. The order is arbitrary.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+    /// assert_eq!(s.iter().sum::<i32>(), 6);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            iter: (&self.map).into_iter(),
+        }
+    }
+
+    /// Returns an iterator over the items in the set in sorted order.
+    ///
+    /// ```
+    /// let s: treap::TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+    /// let v: Vec<_> = s.iter_ordered().cloned().collect();
+    /// assert_eq!(v, vec![1, 2, 3]);
+    /// ```
+    pub fn iter_ordered(&self) -> OrderedIter<'_, T> {
+        OrderedIter {
+            iter: self.map.iter_ordered(),
+        }
+    }
+
+    /// Returns a lazy iterator over the items in `self` or `other`, in sorted order.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+    /// let b: treap::TreapSet<_> = vec![3, 4].into_iter().collect();
+    /// let v: Vec<_> = a.union(&b).cloned().collect();
+    /// assert_eq!(v, vec![1, 2, 3, 4]);
+    /// ```
+    pub fn union<'a>(&'a self, other: &'a TreapSet<T>) -> Union<'a, T> {
+        Union {
+            a: self.iter_ordered().peekable(),
+            b: other.iter_ordered().peekable(),
+        }
+    }
+
+    /// Returns a lazy iterator over the items in both `self` and `other`, in sorted order.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+    /// let b: treap::TreapSet<_> = vec![2, 3, 4].into_iter().collect();
+    /// let v: Vec<_> = a.intersection(&b).cloned().collect();
+    /// assert_eq!(v, vec![2, 3]);
+    /// ```
+    pub fn intersection<'a>(&'a self, other: &'a TreapSet<T>) -> Intersection<'a, T> {
+        Intersection {
+            a: self.iter_ordered().peekable(),
+            b: other.iter_ordered().peekable(),
+        }
+    }
+
+    /// Returns a lazy iterator over the items in `self` but not in `other`, in sorted order.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+    /// let b: treap::TreapSet<_> = vec![2, 3, 4].into_iter().collect();
+    /// let v: Vec<_> = a.difference(&b).cloned().collect();
+    /// assert_eq!(v, vec![1]);
+    /// ```
+    pub fn difference<'a>(&'a self, other: &'a TreapSet<T>) -> Difference<'a, T> {
+        Difference {
+            a: self.iter_ordered().peekable(),
+            b: other.iter_ordered().peekable(),
+        }
+    }
+
+    /// Returns a lazy iterator over the items in exactly one of `self` and `other`, in sorted
+    /// order.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+    /// let b: treap::TreapSet<_> = vec![2, 3, 4].into_iter().collect();
+    /// let v: Vec<_> = a.symmetric_difference(&b).cloned().collect();
+    /// assert_eq!(v, vec![1, 4]);
+    /// ```
+    pub fn symmetric_difference<'a>(
+        &'a self,
+        other: &'a TreapSet<T>,
+    ) -> SymmetricDifference<'a, T> {
+        SymmetricDifference {
+            a: self.iter_ordered().peekable(),
+            b: other.iter_ordered().peekable(),
+        }
+    }
+
+    /// Returns true if every item in `self` is also in `other`. The check follows the structure
+    /// of `other` and returns as soon as a missing item is found.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![2, 4].into_iter().collect();
+    /// let b: treap::TreapSet<_> = (0..10).collect();
+    /// assert!(a.is_subset(&b));
+    /// assert!(!b.is_subset(&a));
+    /// ```
+    pub fn is_subset(&self, other: &TreapSet<T>) -> bool {
+        self.len() <= other.len() && Node::is_subset(self.map.tree(), other.map.tree())
+    }
+
+    /// Returns true if every item in `other` is also in `self`.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = (0..10).collect();
+    /// let b: treap::TreapSet<_> = vec![2, 4].into_iter().collect();
+    /// assert!(a.is_superset(&b));
+    /// ```
+    pub fn is_superset(&self, other: &TreapSet<T>) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns true if `self` and `other` have no items in common. The check follows the
+    /// structure of the smaller set, looks up its items in the larger one and returns as soon as
+    /// a common item is found.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = vec![1, 3, 5].into_iter().collect();
+    /// let b: treap::TreapSet<_> = vec![2, 4].into_iter().collect();
+    /// assert!(a.is_disjoint(&b));
+    /// ```
+    pub fn is_disjoint(&self, other: &TreapSet<T>) -> bool {
+        let (small, large) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Node::is_disjoint(large.map.tree(), small.map.tree())
+    }
+
+    /// Consume both sets and return their union. Runs in O(m log(n/m)) expected time where m is
+    /// the size of the smaller set, by splitting and joining the treaps. This is also available
+    /// as the `|` operator on owned sets.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = (0..10).collect();
+    /// let b: treap::TreapSet<_> = (5..15).collect();
+    /// let c = a.into_union(b);
+    /// assert_eq!(c.len(), 15);
+    /// ```
+    pub fn into_union(mut self, other: TreapSet<T>) -> TreapSet<T> {
+        self.map.combine(other.map, Node::union);
+        self
+    }
+
+    /// Consume both sets and return their intersection. Runs in O(m log(n/m)) expected time
+    /// where m is the size of the smaller set. This is also available as the `&` operator on owned
+    /// sets.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = (0..10).collect();
+    /// let b: treap::TreapSet<_> = (5..15).collect();
+    /// let c = a.into_intersection(b);
+    /// assert_eq!(c.iter_ordered().cloned().collect::<Vec<_>>(), vec![5, 6, 7, 8, 9]);
+    /// ```
+    pub fn into_intersection(mut self, other: TreapSet<T>) -> TreapSet<T> {
+        self.map.combine(other.map, Node::intersection);
+        self
+    }
+
+    /// Consume both sets and return the items of `self` that are not in `other`. Runs in
+    /// O(m log(n/m)) expected time where m is the size of the smaller set. This is also available
+    /// as the `-` operator on owned sets.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = (0..10).collect();
+    /// let b: treap::TreapSet<_> = (5..15).collect();
+    /// let c = a.into_difference(b);
+    /// assert_eq!(c.iter_ordered().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    /// ```
+    pub fn into_difference(mut self, other: TreapSet<T>) -> TreapSet<T> {
+        self.map.combine(other.map, Node::difference);
+        self
+    }
+
+    /// Consume both sets and return the items that are in exactly one of them. Runs in
+    /// O(m log(n/m)) expected time where m is the size of the smaller set. This is also available
+    /// as the `^` operator on owned sets.
+    ///
+    /// ```
+    /// let a: treap::TreapSet<_> = (0..10).collect();
+    /// let b: treap::TreapSet<_> = (5..15).collect();
+    /// let c = a.into_symmetric_difference(b);
+    /// assert_eq!(c.len(), 10);
+    /// assert!(!c.contains(&7));
+    /// ```
+    pub fn into_symmetric_difference(mut self, other: TreapSet<T>) -> TreapSet<T> {
+        self.map.combine(other.map, Node::symmetric_difference);
+        self
+    }
+}
+
+impl<T: Ord> BitOr for TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitor(self, rhs: TreapSet<T>) -> TreapSet<T> {
+        self.into_union(rhs)
+    }
+}
+
+impl<T: Ord> BitAnd for TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitand(self, rhs: TreapSet<T>) -> TreapSet<T> {
+        self.into_intersection(rhs)
+    }
+}
+
+impl<T: Ord> Sub for TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn sub(self, rhs: TreapSet<T>) -> TreapSet<T> {
+        self.into_difference(rhs)
+    }
+}
+
+impl<T: Ord> BitXor for TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitxor(self, rhs: TreapSet<T>) -> TreapSet<T> {
+        self.into_symmetric_difference(rhs)
+    }
+}
+
+impl<T: Ord + Clone> BitOr<&TreapSet<T>> for &TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitor(self, rhs: &TreapSet<T>) -> TreapSet<T> {
+        TreapSet::from_sorted_iter(self.union(rhs).cloned())
+    }
+}
+
+impl<T: Ord + Clone> BitAnd<&TreapSet<T>> for &TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitand(self, rhs: &TreapSet<T>) -> TreapSet<T> {
+        TreapSet::from_sorted_iter(self.intersection(rhs).cloned())
+    }
+}
+
+impl<T: Ord + Clone> Sub<&TreapSet<T>> for &TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn sub(self, rhs: &TreapSet<T>) -> TreapSet<T> {
+        TreapSet::from_sorted_iter(self.difference(rhs).cloned())
+    }
+}
+
+impl<T: Ord + Clone> BitXor<&TreapSet<T>> for &TreapSet<T> {
+    type Output = TreapSet<T>;
+
+    fn bitxor(self, rhs: &TreapSet<T>) -> TreapSet<T> {
+        TreapSet::from_sorted_iter(self.symmetric_difference(rhs).cloned())
+    }
+}
+
+impl<T: Ord> PartialEq for TreapSet<T> {
+    fn eq(&self, other: &TreapSet<T>) -> bool {
+        self.len() == other.len() && self.iter_ordered().eq(other.iter_ordered())
+    }
+}
+
+impl<T: Ord> Eq for TreapSet<T> {}
+
+impl<T: Ord> PartialOrd for TreapSet<T> {
+    fn partial_cmp(&self, other: &TreapSet<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> Ord for TreapSet<T> {
+    fn cmp(&self, other: &TreapSet<T>) -> Ordering {
+        self.iter_ordered().cmp(other.iter_ordered())
+    }
+}
+
+impl<T: Ord + Hash> Hash for TreapSet<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for item in self.iter_ordered() {
+            item.hash(state);
+        }
+    }
+}
+
+impl<T: Ord + fmt::Debug> fmt::Debug for TreapSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter_ordered()).finish()
+    }
+}
+
+impl<T: Ord> Extend<T> for TreapSet<T> {
+    #[inline]
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.insert(item);
+        }
+    }
+}
+
+impl<T: Ord> FromIterator<T> for TreapSet<T> {
+    #[inline]
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> TreapSet<T> {
+        let mut set = TreapSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+/// Return an iterator that moves items out of the set. The order is arbitrary.
+///
+/// ```
+/// let s: treap::TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+/// let mut v: Vec<_> = s.into_iter().collect();
+/// v.sort();
+/// assert_eq!(v, vec![1, 2, 3]);
+/// ```
+impl<T: Ord> IntoIterator for TreapSet<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            iter: self.map.into_iter(),
+        }
+    }
+}
+
+impl<'a, T: Ord> IntoIterator for &'a TreapSet<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
 }
 
 impl<T: Ord> Default for TreapSet<T> {
@@ -55,3 +743,377 @@ impl<T: Ord> Default for TreapSet<T> {
         TreapSet::new()
     }
 }
+
+impl<'a, T: Ord, F: FnMut(&T) -> bool> Iterator for ExtractIf<'a, T, F> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let pred = &mut self.pred;
+        self.extraction
+            .next_matching(&mut |item: &T, _: &mut ()| pred(item))
+            .map(|(item, _)| item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.extraction.size_hint()
+    }
+}
+
+impl<'a, T: Ord, F: FnMut(&T) -> bool> FusedIterator for ExtractIf<'a, T, F> {}
+
+impl<'a, T> Iterator for Range<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Range<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        self.iter.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Range<'a, T> {}
+
+impl<'a, T> FusedIterator for Range<'a, T> {}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T> FusedIterator for Iter<'a, T> {}
+
+impl<'a, T> Iterator for OrderedIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<&'a T> {
+        self.iter.nth(n).map(|(k, _)| k)
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for OrderedIter<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        self.iter.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, T> ExactSizeIterator for OrderedIter<'a, T> {}
+
+impl<'a, T> FusedIterator for OrderedIter<'a, T> {}
+
+// Compare the next items of two sorted iterators. `short` is returned when `x` is exhausted and
+// `long` when only `y` is exhausted.
+fn cmp_opt<T: Ord>(x: Option<&T>, y: Option<&T>, short: Ordering, long: Ordering) -> Ordering {
+    match (x, y) {
+        (None, _) => short,
+        (_, None) => long,
+        (Some(x), Some(y)) => x.cmp(y),
+    }
+}
+
+impl<'a, T: Ord> Iterator for Union<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        match cmp_opt(
+            self.a.peek(),
+            self.b.peek(),
+            Ordering::Greater,
+            Ordering::Less,
+        ) {
+            Ordering::Less => self.a.next(),
+            Ordering::Greater => self.b.next(),
+            Ordering::Equal => {
+                self.b.next();
+                self.a.next()
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (cmp::max(a, b), a.checked_add(b))
+    }
+}
+
+impl<'a, T: Ord> FusedIterator for Union<'a, T> {}
+
+impl<'a, T: Ord> Iterator for Intersection<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            match (self.a.peek(), self.b.peek()) {
+                (Some(x), Some(y)) => match x.cmp(y) {
+                    Ordering::Less => {
+                        self.a.next();
+                    }
+                    Ordering::Greater => {
+                        self.b.next();
+                    }
+                    Ordering::Equal => {
+                        self.b.next();
+                        return self.a.next();
+                    }
+                },
+                _ => return None,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(cmp::min(self.a.len(), self.b.len())))
+    }
+}
+
+impl<'a, T: Ord> FusedIterator for Intersection<'a, T> {}
+
+impl<'a, T: Ord> Iterator for Difference<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            match cmp_opt(self.a.peek(), self.b.peek(), Ordering::Less, Ordering::Less) {
+                Ordering::Less => return self.a.next(),
+                Ordering::Equal => {
+                    self.a.next();
+                    self.b.next();
+                }
+                Ordering::Greater => {
+                    self.b.next();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (a.saturating_sub(b), Some(a))
+    }
+}
+
+impl<'a, T: Ord> FusedIterator for Difference<'a, T> {}
+
+impl<'a, T: Ord> Iterator for SymmetricDifference<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            match cmp_opt(
+                self.a.peek(),
+                self.b.peek(),
+                Ordering::Greater,
+                Ordering::Less,
+            ) {
+                Ordering::Less => return self.a.next(),
+                Ordering::Greater => return self.b.next(),
+                Ordering::Equal => {
+                    self.a.next();
+                    self.b.next();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (a, b) = (self.a.len(), self.b.len());
+        (cmp::max(a, b) - cmp::min(a, b), a.checked_add(b))
+    }
+}
+
+impl<'a, T: Ord> FusedIterator for SymmetricDifference<'a, T> {}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<T> ExactSizeIterator for IntoIter<T> {}
+
+impl<T> FusedIterator for IntoIter<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::TreapSet;
+
+    #[test]
+    fn eq_ignores_insertion_order() {
+        let a: TreapSet<_> = (0..100).collect();
+        let b: TreapSet<_> = (0..100).rev().collect();
+        let c: TreapSet<_> = (1..100).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a.clone(), a);
+    }
+
+    #[test]
+    fn into_iter() {
+        let s: TreapSet<_> = (0..50).collect();
+        let mut v: Vec<_> = s.into_iter().collect();
+        v.sort();
+        assert_eq!(v, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn replace_keeps_shape() {
+        let mut s: TreapSet<String> = (0..100).map(|x| x.to_string()).collect();
+        let shape = format!("{:?}", s.map.debug_tree());
+        let item = "42".to_string();
+        let ptr = item.as_ptr();
+        assert_eq!(s.replace(item), Some("42".to_string()));
+        assert_eq!(s.get("42").unwrap().as_ptr(), ptr);
+        assert_eq!(format!("{:?}", s.map.debug_tree()), shape);
+        assert_eq!(s.replace("x".to_string()), None);
+        assert_eq!(s.len(), 101);
+        s.map.check_invariants();
+    }
+
+    #[test]
+    fn debug() {
+        let s: TreapSet<_> = vec![3, 1, 2].into_iter().collect();
+        assert_eq!(format!("{:?}", s), "{1, 2, 3}");
+    }
+
+    #[test]
+    fn owned_set_operations() {
+        let evens = || (0..200).filter(|x| x % 2 == 0).collect::<TreapSet<i32>>();
+        let threes = || (0..200).filter(|x| x % 3 == 0).collect::<TreapSet<i32>>();
+        let expected = |f: &dyn Fn(i32) -> bool| (0..200).filter(|&x| f(x)).collect::<Vec<_>>();
+        let items = |s: TreapSet<i32>| {
+            s.map.check_invariants();
+            s.iter_ordered().cloned().collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            items(evens() | threes()),
+            expected(&|x| x % 2 == 0 || x % 3 == 0)
+        );
+        assert_eq!(items(evens() & threes()), expected(&|x| x % 6 == 0));
+        assert_eq!(
+            items(evens() - threes()),
+            expected(&|x| x % 2 == 0 && x % 3 != 0)
+        );
+        assert_eq!(
+            items(evens() ^ threes()),
+            expected(&|x| (x % 2 == 0) != (x % 3 == 0))
+        );
+        assert_eq!(
+            (evens() | threes()).len(),
+            expected(&|x| x % 2 == 0 || x % 3 == 0).len()
+        );
+    }
+
+    #[test]
+    fn borrowed_set_operations() {
+        let a: TreapSet<_> = (0..100).filter(|x| x % 2 == 0).collect();
+        let b: TreapSet<_> = (0..100).filter(|x| x % 3 == 0).collect();
+
+        assert_eq!(&a | &b, a.clone().into_union(b.clone()));
+        assert_eq!(&a & &b, a.clone().into_intersection(b.clone()));
+        assert_eq!(&a - &b, a.clone().into_difference(b.clone()));
+        assert_eq!(&a ^ &b, a.clone().into_symmetric_difference(b.clone()));
+        assert_eq!(a.difference(&TreapSet::new()).count(), a.len());
+        assert_eq!(a.intersection(&TreapSet::new()).count(), 0);
+    }
+
+    #[test]
+    fn subset_and_disjoint() {
+        let all: TreapSet<_> = (0..300).collect();
+        let evens: TreapSet<_> = (0..300).filter(|x| x % 2 == 0).collect();
+        let odds: TreapSet<_> = (0..300).filter(|x| x % 2 == 1).collect();
+        let mut almost = evens.clone();
+        almost.insert(301);
+
+        assert!(evens.is_subset(&all));
+        assert!(all.is_superset(&evens));
+        assert!(!all.is_subset(&evens));
+        assert!(!almost.is_subset(&all));
+        assert!(evens.is_subset(&evens));
+        assert!(TreapSet::new().is_subset(&evens));
+
+        assert!(evens.is_disjoint(&odds));
+        assert!(odds.is_disjoint(&evens));
+        assert!(!evens.is_disjoint(&all));
+        assert!(evens.is_disjoint(&TreapSet::new()));
+        let one: TreapSet<_> = vec![299].into_iter().collect();
+        assert!(!one.is_disjoint(&odds));
+        assert!(one.is_disjoint(&evens));
+    }
+
+    #[test]
+    fn disjoint_in_either_order() {
+        let large: TreapSet<_> = (0..500).map(|x| x * 3).collect();
+        let apart: TreapSet<_> = vec![1, 700, 1499].into_iter().collect();
+        let common: TreapSet<_> = vec![1, 700, 1497].into_iter().collect();
+        assert!(apart.is_disjoint(&large));
+        assert!(large.is_disjoint(&apart));
+        assert!(!common.is_disjoint(&large));
+        assert!(!large.is_disjoint(&common));
+    }
+
+    #[test]
+    fn set_op_size_hints() {
+        let a: TreapSet<_> = (0..10).collect();
+        let b: TreapSet<_> = (5..20).collect();
+        assert_eq!(a.union(&b).size_hint(), (15, Some(25)));
+        assert_eq!(a.intersection(&b).size_hint(), (0, Some(10)));
+        assert_eq!(b.difference(&a).size_hint(), (5, Some(15)));
+        assert_eq!(a.symmetric_difference(&b).size_hint(), (5, Some(25)));
+
+        let mut union = a.union(&b);
+        assert_eq!(union.by_ref().count(), 20);
+        assert_eq!(union.size_hint(), (0, Some(0)));
+        assert_eq!(union.next(), None);
+        let mut intersection = a.intersection(&b);
+        assert_eq!(intersection.by_ref().count(), 5);
+        assert_eq!(intersection.next(), None);
+        assert_eq!(b.difference(&a).count(), 10);
+        assert_eq!(a.symmetric_difference(&b).count(), 15);
+    }
+
+    #[test]
+    fn ord_and_hash() {
+        use std::collections::HashSet;
+
+        let a: TreapSet<_> = vec![1, 2, 3].into_iter().collect();
+        let b: TreapSet<_> = vec![3, 2, 1].into_iter().collect();
+        let c: TreapSet<_> = vec![1, 3].into_iter().collect();
+        assert!(a < c);
+        assert!(TreapSet::new() < a);
+        assert_eq!(a.cmp(&b), ::std::cmp::Ordering::Equal);
+
+        let snapshots: HashSet<_> = vec![a, b, c].into_iter().collect();
+        assert_eq!(snapshots.len(), 2);
+    }
+}
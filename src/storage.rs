@@ -0,0 +1,237 @@
+//! Node storage
+//!
+//! A `TreapMap` keeps its nodes in a `Storage`, which decides how nodes are allocated and how they
+//! refer to their children. The algorithms of the map are the same for every storage. The default
+//! is `Boxed`, which gives each node its own heap allocation; `Arena` keeps all the nodes of a map
+//! in one vector, and `Shared` reference counts the nodes so that copies of a map share them.
+
+use std::sync::Arc;
+
+use aggregate::Aggregate;
+use node::{self, InorderMut, Link, Linked, Node, PreorderMut, Store};
+
+/// Node storage for a `TreapMap`. This trait is sealed: it is implemented by `Boxed`, `Arena` and
+/// `Shared` and cannot be implemented outside this crate.
+pub trait Storage<K, V, A: Aggregate<K, V> = ()>: Store<K, V, A> {}
+
+/// Storage that allocates each node in its own `Box`. This is the default storage of a
+/// `TreapMap`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Boxed;
+
+impl<K, V, A: Aggregate<K, V>> Storage<K, V, A> for Boxed {}
+
+impl<K, V, A: Aggregate<K, V>> Linked<K, V, A> for Boxed {
+    #[inline]
+    fn get(handle: &Box<Node<K, V, Boxed, A>>) -> &Node<K, V, Boxed, A> {
+        handle
+    }
+
+    #[inline]
+    fn get_mut(handle: &mut Box<Node<K, V, Boxed, A>>) -> &mut Node<K, V, Boxed, A> {
+        handle
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>> Store<K, V, A> for Boxed {
+    type Handle = Box<Node<K, V, Boxed, A>>;
+    type IterMut<'a>
+        = PreorderMut<'a, K, V, Boxed, A>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+    type OrderedIterMut<'a>
+        = InorderMut<'a, K, V, Boxed, A>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+
+    #[inline]
+    fn node<'a>(&'a self, handle: &'a Self::Handle) -> &'a Node<K, V, Boxed, A> {
+        handle
+    }
+
+    #[inline]
+    fn node_mut<'a>(&'a mut self, handle: &'a mut Self::Handle) -> &'a mut Node<K, V, Boxed, A> {
+        handle
+    }
+
+    #[inline]
+    fn alloc(&mut self, node: Node<K, V, Boxed, A>) -> Self::Handle {
+        Box::new(node)
+    }
+
+    #[inline]
+    fn take(&mut self, handle: Self::Handle) -> Node<K, V, Boxed, A> {
+        *handle
+    }
+
+    fn drop_tree(&mut self, subtree: Link<K, V, Boxed, A>) {
+        // Dropping a box drops its children recursively, which could overflow the stack on a
+        // degenerate tree. Detach the children first so that every node is dropped as a leaf.
+        let mut stack: Vec<_> = subtree.into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.left.take());
+            stack.extend(node.right.take());
+        }
+    }
+
+    fn descend_mut<'a, F>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Boxed, A>,
+        step: F,
+    ) -> Option<&'a mut Node<K, V, Boxed, A>>
+    where
+        F: FnMut(&Node<K, V, Boxed, A>) -> Option<bool>,
+    {
+        node::descend_linked(subtree, step)
+    }
+
+    fn iter_mut<'a>(&'a mut self, subtree: &'a mut Link<K, V, Boxed, A>) -> Self::IterMut<'a> {
+        PreorderMut::new(subtree)
+    }
+
+    fn ordered_mut<'a>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Boxed, A>,
+        skip: usize,
+        len: usize,
+    ) -> Self::OrderedIterMut<'a> {
+        InorderMut::new(subtree, skip, len)
+    }
+
+    #[inline]
+    fn adopt(&mut self, _other: &mut Boxed, subtree: Link<K, V, Boxed, A>) -> Link<K, V, Boxed, A> {
+        subtree
+    }
+
+    fn clone_tree(&self, subtree: &Link<K, V, Boxed, A>) -> (Boxed, Link<K, V, Boxed, A>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        // Copy the nodes in pre-order, each into the empty link left for it in its parent's copy,
+        // so a degenerate tree does not overflow the stack
+        let mut root = None;
+        let mut stack = vec![(subtree.as_deref(), &mut root)];
+        while let Some((node, link)) = stack.pop() {
+            if let Some(node) = node {
+                let copy = link.insert(Box::new(node.copy_detached()));
+                stack.push((node.right.as_deref(), &mut copy.right));
+                stack.push((node.left.as_deref(), &mut copy.left));
+            }
+        }
+        (Boxed, root)
+    }
+}
+
+/// Storage that keeps each node behind an `Arc`, so that a map and its clones share their nodes.
+/// Cloning a map with this storage is O(1). A node that is shared is copied before it is changed,
+/// so a change to one map copies only the nodes on the paths it walks and never affects the
+/// others. This storage needs the keys and values to be `Clone` to copy nodes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Shared;
+
+impl<K: Clone, V: Clone, A: Aggregate<K, V>> Storage<K, V, A> for Shared {}
+
+impl<K: Clone, V: Clone, A: Aggregate<K, V>> Linked<K, V, A> for Shared {
+    #[inline]
+    fn get(handle: &Arc<Node<K, V, Shared, A>>) -> &Node<K, V, Shared, A> {
+        handle
+    }
+
+    #[inline]
+    fn get_mut(handle: &mut Arc<Node<K, V, Shared, A>>) -> &mut Node<K, V, Shared, A> {
+        Arc::make_mut(handle)
+    }
+}
+
+impl<K: Clone, V: Clone, A: Aggregate<K, V>> Store<K, V, A> for Shared {
+    type Handle = Arc<Node<K, V, Shared, A>>;
+    type IterMut<'a>
+        = PreorderMut<'a, K, V, Shared, A>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+    type OrderedIterMut<'a>
+        = InorderMut<'a, K, V, Shared, A>
+    where
+        K: 'a,
+        V: 'a,
+        A: 'a;
+
+    #[inline]
+    fn node<'a>(&'a self, handle: &'a Self::Handle) -> &'a Node<K, V, Shared, A> {
+        handle
+    }
+
+    #[inline]
+    fn node_mut<'a>(&'a mut self, handle: &'a mut Self::Handle) -> &'a mut Node<K, V, Shared, A> {
+        Arc::make_mut(handle)
+    }
+
+    #[inline]
+    fn alloc(&mut self, node: Node<K, V, Shared, A>) -> Self::Handle {
+        Arc::new(node)
+    }
+
+    #[inline]
+    fn take(&mut self, handle: Self::Handle) -> Node<K, V, Shared, A> {
+        Arc::try_unwrap(handle).unwrap_or_else(|shared| (*shared).clone())
+    }
+
+    fn drop_tree(&mut self, subtree: Link<K, V, Shared, A>) {
+        // Only the nodes that are not shared with another tree are released, each as a leaf
+        let mut stack: Vec<_> = subtree.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Ok(mut node) = Arc::try_unwrap(node) {
+                stack.extend(node.left.take());
+                stack.extend(node.right.take());
+            }
+        }
+    }
+
+    fn descend_mut<'a, F>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Shared, A>,
+        step: F,
+    ) -> Option<&'a mut Node<K, V, Shared, A>>
+    where
+        F: FnMut(&Node<K, V, Shared, A>) -> Option<bool>,
+    {
+        node::descend_linked(subtree, step)
+    }
+
+    fn iter_mut<'a>(&'a mut self, subtree: &'a mut Link<K, V, Shared, A>) -> Self::IterMut<'a> {
+        PreorderMut::new(subtree)
+    }
+
+    fn ordered_mut<'a>(
+        &'a mut self,
+        subtree: &'a mut Link<K, V, Shared, A>,
+        skip: usize,
+        len: usize,
+    ) -> Self::OrderedIterMut<'a> {
+        InorderMut::new(subtree, skip, len)
+    }
+
+    #[inline]
+    fn adopt(
+        &mut self,
+        _other: &mut Shared,
+        subtree: Link<K, V, Shared, A>,
+    ) -> Link<K, V, Shared, A> {
+        subtree
+    }
+
+    fn clone_tree(&self, subtree: &Link<K, V, Shared, A>) -> (Shared, Link<K, V, Shared, A>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        (Shared, subtree.clone())
+    }
+}